@@ -0,0 +1,68 @@
+//! Accessibility: relay the AT-SPI bus of the invoking user's session, so that screen readers and
+//! other assistive technologies work with the target user's applications.
+//!
+//! The accessibility bus is a separate D-Bus daemon that only accepts connections from its owner,
+//! so it cannot be shared with ACLs; it is always relayed (see [`crate::relay`]). Only done with
+//! `--a11y`, because the relay authenticates the target user as the bus owner.
+
+use crate::errors::AnyErr;
+use crate::relay::{A11Y_BUS, RelaySocket, parse_bus_address};
+use crate::{EgoContext, getenv_optional};
+use log::{debug, warn};
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedValue;
+
+const A11Y_NAME: &str = "org.a11y.Bus";
+const A11Y_PATH: &str = "/org/a11y/bus";
+
+/// Whether assistive technologies are enabled in the invoking user's session.
+fn a11y_enabled(session: &Connection) -> Result<bool, AnyErr> {
+    let reply = session.call_method(
+        Some(A11Y_NAME),
+        A11Y_PATH,
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &("org.a11y.Status", "IsEnabled"),
+    )?;
+    let value: OwnedValue = reply.body().deserialize()?;
+    Ok(bool::try_from(value)?)
+}
+
+fn find_a11y_socket() -> Result<Option<RelaySocket>, AnyErr> {
+    let address = if let Some(address) = getenv_optional("AT_SPI_BUS_ADDRESS")? {
+        address
+    } else {
+        let session = Connection::session()?;
+        if !a11y_enabled(&session)? {
+            debug!("Accessibility is not enabled, skipping");
+            return Ok(None);
+        }
+        let reply =
+            session.call_method(Some(A11Y_NAME), A11Y_PATH, Some(A11Y_NAME), "GetAddress", &())?;
+        reply.body().deserialize()?
+    };
+    let Some(target) = parse_bus_address(&address) else {
+        warn!("Accessibility bus address '{address}' is not supported, skipping");
+        return Ok(None);
+    };
+    Ok(Some(RelaySocket { name: A11Y_BUS, target, env_prefix: "AT_SPI_BUS_ADDRESS=unix:path=" }))
+}
+
+/// Locate the accessibility bus of the invoking user, if assistive technologies are enabled.
+/// Failures only cause a warning, accessibility is not essential for most applications.
+pub fn a11y_socket(ctx: &EgoContext) -> Option<RelaySocket> {
+    match find_a11y_socket() {
+        Ok(Some(socket)) => {
+            warn!(
+                "--a11y: user {} can read and control your accessible applications",
+                ctx.target_user
+            );
+            Some(socket)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            warn!("Accessibility bus not available: {err}");
+            None
+        }
+    }
+}
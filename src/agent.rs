@@ -0,0 +1,114 @@
+//! Forwarding of ssh-agent and gpg-agent sockets to the target user (`--ssh-agent`,
+//! `--gpg-agent`).
+
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::relay::{RelaySocket, create_relay_dir};
+use crate::share::{adopt_held, grant_parents, live_held, revoke_on_exit};
+use crate::util::{have_command, run_command};
+use crate::{EgoContext, Grant, getenv_optional};
+use log::{debug, warn};
+use posix_acl::{ACL_EXECUTE, ACL_RWX};
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable that tells the target user's setup script where our gpg-agent socket is
+const GPG_SOCK_ENV: &str = "EGO_GPG_AGENT_SOCK=";
+
+/// Environment variable with the per-launch `GNUPGHOME` that ego created in the relay dir
+const GNUPGHOME_ENV: &str = "EGO_GNUPGHOME=";
+
+/// Point `gpg` at our forwarded socket through a per-launch `GNUPGHOME`, so that the target user's
+/// own agent socket is left alone. The new home links to everything in the user's `gpg` home
+/// except sockets. With `--relay`, it is in the relay dir and removed along with it.
+pub const GPG_SETUP_SCRIPT: &str = "if h=$(gpgconf --list-dirs homedir) && \
+    d=${EGO_GNUPGHOME:-$(mktemp -d \"${XDG_RUNTIME_DIR:-/tmp}/ego-gnupg.XXXXXX\")}; then \
+    for f in \"$h\"/*; do case ${f##*/} in S.*) ;; \
+    *) [ -e \"$f\" ] && ln -s \"$f\" \"$d/\";; esac; done; \
+    export GNUPGHOME=\"$d\" && s=$(gpgconf --list-dirs agent-socket) && \
+    mkdir -p -m 0700 \"${s%/*}\" && ln -sf \"$EGO_GPG_AGENT_SOCK\" \"$s\"; fi";
+
+/// Grant access to socket `path`, including traverse permission on its parent directories. The
+/// permissions are revoked again when the command exits, except on the runtime dir, which the
+/// session needs anyway. With `--relay`, the socket is relayed by the session's relay instead,
+/// and `env_prefix` is set to the relayed socket.
+fn grant_socket(
+    ctx: &EgoContext,
+    name: &'static str,
+    path: &Path,
+    env_prefix: &'static str,
+) -> Result<Grant, AnyErr> {
+    if !path.exists() {
+        bail!("Socket '{}' not found", path.display());
+    }
+    let mut grant = Grant::default();
+    if ctx.relay {
+        let socket = RelaySocket { name, target: path.to_path_buf(), env_prefix };
+        grant.relayed.push(socket);
+        return Ok(grant);
+    }
+    let held = live_held()?;
+    grant.add_acl(ctx, path, ACL_RWX)?;
+    let mut acls = vec![(ACL_RWX, path.to_path_buf())];
+    for dir in grant_parents(ctx, path, &mut grant)? {
+        acls.push((ACL_EXECUTE, dir));
+    }
+    adopt_held(ctx, &held, path, &mut acls);
+    acls.retain(|(_, dir)| *dir != ctx.runtime_dir);
+    revoke_on_exit(ctx, &acls)?;
+    grant.env.push(format!("{env_prefix}{}", path.display()));
+    Ok(grant)
+}
+
+/// Grant access to the socket in `SSH_AUTH_SOCK`, like `prepare_wayland` does for Wayland.
+pub fn prepare_ssh_agent(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let Some(sock) = getenv_optional("SSH_AUTH_SOCK")? else {
+        return Err(ErrorWithHint::new(
+            "SSH_AUTH_SOCK is not set".into(),
+            "Start ssh-agent or a keyring that provides it".into(),
+        )
+        .into());
+    };
+    let path = PathBuf::from(sock);
+    let grant = grant_socket(ctx, "ssh-agent", &path, "SSH_AUTH_SOCK=")?;
+
+    warn!(
+        "--ssh-agent: user {} can authenticate as you with all keys in your SSH agent",
+        ctx.target_user
+    );
+    debug!("SSH agent socket '{}' configured", path.display());
+    Ok(grant)
+}
+
+/// Grant access to the restricted gpg-agent extra socket. The target user's agent socket path is
+/// linked to it before the command runs.
+pub fn prepare_gpg_agent(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    if !have_command("gpgconf") {
+        return Err(ErrorWithHint::new(
+            "gpgconf is not installed".into(),
+            "Try installing package that contains command 'gpgconf'".into(),
+        )
+        .into());
+    }
+    let args = ["--list-dirs", "agent-extra-socket"].map(String::from);
+    let output = run_command("gpgconf", &args)?;
+    let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let mut grant = grant_socket(ctx, "gpg-agent", &path, GPG_SOCK_ENV)?;
+    if ctx.relay {
+        let home = create_relay_dir(ctx, &mut grant)?.join("gnupg");
+        if !ctx.dry_run {
+            DirBuilder::new().mode(0o700).create(&home)?;
+        }
+        grant.add_acl(ctx, &home, ACL_RWX)?;
+        grant.env.push(format!("{GNUPGHOME_ENV}{}", home.display()));
+    }
+    grant.setup.push(GPG_SETUP_SCRIPT.to_string());
+
+    warn!(
+        "--gpg-agent: user {} can sign and decrypt with your GnuPG keys (via the restricted extra \
+        socket)",
+        ctx.target_user
+    );
+    debug!("GnuPG agent extra socket '{}' configured", path.display());
+    Ok(grant)
+}
@@ -1,23 +1,173 @@
-use clap::{Arg, ArgAction, ArgGroup, Command, ValueHint, command};
+use clap::{Arg, ArgAction, ArgGroup, Command, ValueHint, command, value_parser};
 use log::Level;
 use std::ffi::OsString;
+use std::path::PathBuf;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Method {
     Sudo,
     Machinectl,
     MachinectlBare,
 }
 
+impl Method {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Sudo => "sudo",
+            Method::Machinectl => "machinectl",
+            Method::MachinectlBare => "machinectl-bare",
+        }
+    }
+
+    /// Command line flag that selects this method
+    #[must_use]
+    pub fn as_flag(self) -> &'static str {
+        match self {
+            Method::Sudo => "--sudo",
+            Method::Machinectl => "--machinectl",
+            Method::MachinectlBare => "--machinectl-bare",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl ShareMode {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShareMode::ReadOnly => "ro",
+            ShareMode::ReadWrite => "rw",
+        }
+    }
+}
+
+/// Parsed `--share PATH[:ro|rw]` argument
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareSpec {
+    pub path: PathBuf,
+    pub mode: ShareMode,
+}
+
+/// Subcommands that do something other than launching a command
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    InstallLauncher {
+        entry: String,
+    },
+    UninstallLauncher {
+        entry: String,
+    },
+    Setup {
+        user: String,
+        group: String,
+        dry_run: bool,
+    },
+    Doctor {
+        json: bool,
+    },
+    Prepare {
+        print_env: bool,
+        format: EnvFormat,
+    },
+    History,
+    Again {
+        num: Option<usize>,
+    },
+    Askpass {
+        prompt: Option<String>,
+    },
+    Relay {
+        parent: u32,
+        dir: PathBuf,
+        sockets: Vec<String>,
+    },
+    Revoke {
+        parent: u32,
+        uid: u32,
+        acls: Vec<String>,
+    },
+    NotifyBridge {
+        parent: u32,
+        prefix: Option<String>,
+        socket: PathBuf,
+    },
+    NotifyProxy {
+        socket: PathBuf,
+    },
+    OpenListener {
+        parent: u32,
+        user: String,
+        socket: PathBuf,
+    },
+    DeviceAcl {
+        user: String,
+        revoke: bool,
+        paths: Vec<PathBuf>,
+    },
+    OpenUrl {
+        args: Vec<String>,
+    },
+}
+
+/// Output format of `ego prepare --print-env`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvFormat {
+    Sh,
+    Json,
+    Env,
+}
+
+/// How notifications are forwarded with `--notifications`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyMode {
+    Plain,
+    /// Prefix summary with target user name
+    Prefix,
+}
+
+/// Format of error and log messages (`--message-format`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    /// JSON lines on stderr, for GUI front-ends
+    Json,
+}
+
 /// Data type for parsed settings
+#[allow(clippy::struct_excessive_bools)]
 pub struct Args {
+    pub action: Option<Action>,
     pub user: String,
     pub command: Vec<String>,
     pub log_level: Level,
+    pub message_format: MessageFormat,
     pub method: Option<Method>,
     pub old_xhost: bool,
+    pub share: Vec<ShareSpec>,
+    pub unshare: Vec<PathBuf>,
+    pub device: Vec<String>,
+    pub list_shares: bool,
+    pub grant_files: Option<ShareMode>,
+    pub desktop_file: Option<String>,
+    pub flatpak: Option<String>,
+    pub machine: Option<String>,
+    pub dry_run: bool,
+    pub relay: bool,
+    pub a11y: bool,
+    pub ibus: bool,
+    pub ssh_agent: bool,
+    pub gpg_agent: bool,
+    pub notifications: Option<NotifyMode>,
+    pub open_links: bool,
 }
 
+#[allow(clippy::too_many_lines)]
 pub fn build_cli() -> Command {
     command!()
         .arg(
@@ -48,12 +198,133 @@ pub fn build_cli() -> Command {
                 .help("Use 'machinectl' but skip xdg-desktop-portal setup"),
         )
         .group(ArgGroup::new("method").args(["sudo", "machinectl", "machinectl-bare"]))
+        .arg(
+            Arg::new("machine")
+                .long("machine")
+                .value_name("NAME")
+                .conflicts_with_all(["sudo", "notifications", "open-links"])
+                .help("Launch in a systemd-nspawn machine or container instead of the host"),
+        )
         .arg(
             Arg::new("old-xhost")
                 .long("old-xhost")
                 .action(ArgAction::SetTrue)
                 .help("Execute 'xhost' command instead of connecting to X11 directly"),
         )
+        .arg(
+            Arg::new("share")
+                .long("share")
+                .value_name("PATH[:ro|rw]")
+                .action(ArgAction::Append)
+                .value_parser(parse_share_spec)
+                .help("Grant target user access to a file or directory tree (default: ro)")
+                .value_hint(ValueHint::AnyPath),
+        )
+        .arg(
+            Arg::new("unshare")
+                .long("unshare")
+                .value_name("PATH")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(PathBuf))
+                .help("Revoke access previously granted with --share or --device")
+                .value_hint(ValueHint::AnyPath),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .value_name("PATH|CLASS")
+                .action(ArgAction::Append)
+                .help("Grant target user access to a device node or class: camera, gamepad, audio")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("ssh-agent")
+                .long("ssh-agent")
+                .action(ArgAction::SetTrue)
+                .help("Forward your SSH agent to target user (target can use all your keys)"),
+        )
+        .arg(
+            Arg::new("gpg-agent")
+                .long("gpg-agent")
+                .action(ArgAction::SetTrue)
+                .help("Forward your GnuPG agent's restricted extra socket to target user"),
+        )
+        .arg(
+            Arg::new("notifications")
+                .long("notifications")
+                .value_name("MODE")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("plain")
+                .value_parser(["plain", "prefix"])
+                .help("Show target user's notifications on your desktop, optionally prefixed"),
+        )
+        .arg(
+            Arg::new("open-links")
+                .long("open-links")
+                .action(ArgAction::SetTrue)
+                .help("Open links from target user's applications in your browser, after asking"),
+        )
+        .arg(
+            Arg::new("list-shares")
+                .long("list-shares")
+                .action(ArgAction::SetTrue)
+                .help("List paths shared with --share"),
+        )
+        .arg(
+            Arg::new("grant-args")
+                .long("grant-args")
+                .value_name("ro|rw")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("ro")
+                .value_parser(["ro", "rw"])
+                .help("Grant target user access to files given as command arguments"),
+        )
+        .arg(
+            Arg::new("desktop-file")
+                .long("desktop-file")
+                .value_name("ENTRY")
+                .help("Run application from .desktop file or application id, command is file list")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("flatpak")
+                .long("flatpak")
+                .value_name("APP_ID")
+                .conflicts_with("desktop-file")
+                .help("Run Flatpak application, command is its arguments"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print planned permission changes and command without changing anything"),
+        )
+        .arg(
+            Arg::new("relay")
+                .long("relay")
+                .action(ArgAction::SetTrue)
+                .help("Relay display and audio sockets instead of granting access to runtime dir"),
+        )
+        .arg(
+            Arg::new("a11y")
+                .long("a11y")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Relay your accessibility bus for screen readers. Warning: target user can \
+                    read and control your accessible applications",
+                ),
+        )
+        .arg(
+            Arg::new("ibus")
+                .long("ibus")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Relay your IBus input method bus on X11 instead of using XIM. Warning: target \
+                    user can see what you type",
+                ),
+        )
         .arg(
             Arg::new("command")
                 .help("Command name and arguments to run (default: user shell)")
@@ -68,12 +339,349 @@ pub fn build_cli() -> Command {
                 .action(ArgAction::Count)
                 .help("Verbose output. Use multiple times for more output."),
         )
+        .arg(
+            Arg::new("message-format")
+                .long("message-format")
+                .value_name("FORMAT")
+                .value_parser(["human", "json"])
+                .default_value("human")
+                .help("Output errors and warnings as text or as JSON lines on stderr"),
+        )
+        .subcommand(
+            Command::new("install-launcher")
+                .about("Install a copy of a desktop entry that launches the application via ego")
+                .arg(desktop_entry_arg()),
+        )
+        .subcommand(
+            Command::new("uninstall-launcher")
+                .about("Remove a desktop entry installed with install-launcher")
+                .arg(desktop_entry_arg()),
+        )
+        .subcommand(setup_command())
+        .subcommand(
+            Command::new("doctor")
+                .about("Diagnose common setup problems without launching anything")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Output results as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("prepare")
+                .about("Grant access to display and audio, but do not launch a command")
+                .arg(
+                    Arg::new("print-env")
+                        .long("print-env")
+                        .action(ArgAction::SetTrue)
+                        .help("Print environment variables for the target user"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["sh", "json", "env"])
+                        .default_value("sh")
+                        .help("Format of --print-env: shell, JSON or systemd EnvironmentFile"),
+                ),
+        )
+        .subcommand(Command::new("history").about("List previous launches"))
+        .subcommand(
+            Command::new("again")
+                .about("Re-run a previous launch with the same options")
+                .arg(
+                    Arg::new("num")
+                        .value_name("N")
+                        .value_parser(value_parser!(usize))
+                        .help("Entry number from 'ego history' (default: latest)"),
+                ),
+        )
+        .subcommand(
+            Command::new("askpass")
+                .about("Ask for password with a graphical dialog (used as SUDO_ASKPASS)")
+                .hide(true)
+                .arg(Arg::new("prompt").value_name("PROMPT").help("Prompt text")),
+        )
+        .subcommand(
+            Command::new("relay")
+                .about("Relay sockets for --relay mode (started by ego itself)")
+                .hide(true)
+                .arg(
+                    Arg::new("parent")
+                        .long("parent")
+                        .value_name("PID")
+                        .required(true)
+                        .value_parser(value_parser!(u32))
+                        .help("Stop relaying when this process exits"),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory to create listening sockets in"),
+                )
+                .arg(
+                    Arg::new("sockets")
+                        .value_name("NAME=TARGET")
+                        .num_args(1..)
+                        .help("Socket name in DIR and the socket to forward to"),
+                ),
+        )
+        .subcommand(
+            Command::new("revoke")
+                .about("Revoke access granted for a launch (started by ego itself)")
+                .hide(true)
+                .arg(
+                    Arg::new("parent")
+                        .long("parent")
+                        .value_name("PID")
+                        .required(true)
+                        .value_parser(value_parser!(u32))
+                        .help("Revoke when this process exits"),
+                )
+                .arg(
+                    Arg::new("uid")
+                        .long("uid")
+                        .value_name("UID")
+                        .required(true)
+                        .value_parser(value_parser!(u32))
+                        .help("Target user ID"),
+                )
+                .arg(
+                    Arg::new("acls")
+                        .value_name("PERMS:PATH")
+                        .num_args(1..)
+                        .help("Permissions to remove, e.g. r--:/home/me/file.pdf"),
+                ),
+        )
+        .subcommand(
+            Command::new("notify-bridge")
+                .about("Forward notifications to your desktop (started by ego itself)")
+                .hide(true)
+                .arg(
+                    Arg::new("parent")
+                        .long("parent")
+                        .value_name("PID")
+                        .required(true)
+                        .value_parser(value_parser!(u32))
+                        .help("Stop when this process exits"),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .value_name("NAME")
+                        .help("Prefix notification summaries with NAME"),
+                )
+                .arg(
+                    Arg::new("socket")
+                        .value_name("SOCKET")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Socket to listen on"),
+                ),
+        )
+        .subcommand(
+            Command::new("notify-proxy")
+                .about("Forward target user's notifications to the bridge (started by ego itself)")
+                .hide(true)
+                .arg(
+                    Arg::new("socket")
+                        .value_name("SOCKET")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Bridge socket to connect to"),
+                ),
+        )
+        .subcommand(
+            Command::new("open-listener")
+                .about("Open target user's links in your browser (started by ego itself)")
+                .hide(true)
+                .arg(
+                    Arg::new("parent")
+                        .long("parent")
+                        .value_name("PID")
+                        .required(true)
+                        .value_parser(value_parser!(u32))
+                        .help("Stop when this process exits"),
+                )
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .value_name("USER")
+                        .required(true)
+                        .help("Target user name shown in confirmation"),
+                )
+                .arg(
+                    Arg::new("socket")
+                        .value_name("SOCKET")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Socket to listen on"),
+                ),
+        )
+        .subcommand(
+            Command::new("device-acl")
+                .about("Grant target user access to your devices, as root (run by ego via sudo)")
+                .hide(true)
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .value_name("USER")
+                        .required(true)
+                        .help("Target user name"),
+                )
+                .arg(
+                    Arg::new("revoke")
+                        .long("revoke")
+                        .action(ArgAction::SetTrue)
+                        .help("Remove the target user's access instead"),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .value_name("DEVICE")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Device nodes"),
+                ),
+        )
+        .subcommand(
+            Command::new("open-url")
+                .about("Open link in the invoking user's browser (xdg-open replacement)")
+                .hide(true)
+                .arg(
+                    Arg::new("args")
+                        .value_name("URL")
+                        .num_args(0..)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)
+                        .help("Link to open, other arguments are passed to xdg-open"),
+                ),
+        )
+}
+
+fn setup_command() -> Command {
+    Command::new("setup")
+        .about("Create target user and install sudoers and polkit rules (run as root)")
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_name("NAME")
+                .help("Target user to create (default: --user of ego, i.e. 'ego')")
+                .value_hint(ValueHint::Username),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .value_name("NAME")
+                .default_value("ego-users")
+                .help("Group whose members may run commands as target user"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print files and commands without changing anything"),
+        )
+}
+
+fn desktop_entry_arg() -> Arg {
+    Arg::new("entry")
+        .value_name("ENTRY")
+        .required(true)
+        .help("Path to .desktop file or application id")
+        .value_hint(ValueHint::FilePath)
+}
+
+/// Parse `PATH[:ro|rw]`. A suffix other than `:ro` or `:rw` is considered part of the path.
+pub fn parse_share_spec(value: &str) -> Result<ShareSpec, String> {
+    let (path, mode) = match value.rsplit_once(':') {
+        Some((path, "ro")) => (path, ShareMode::ReadOnly),
+        Some((path, "rw")) => (path, ShareMode::ReadWrite),
+        _ => (value, ShareMode::ReadOnly),
+    };
+    if path.is_empty() {
+        return Err("path may not be empty".into());
+    }
+    Ok(ShareSpec { path: PathBuf::from(path), mode })
 }
 
+#[allow(clippy::too_many_lines)]
 pub fn parse_args<T: Into<OsString> + Clone>(args: impl IntoIterator<Item = T>) -> Args {
     let matches = build_cli().get_matches_from(args);
 
     Args {
+        action: match matches.subcommand() {
+            Some(("install-launcher", sub)) => Some(Action::InstallLauncher {
+                entry: sub.get_one::<String>("entry").unwrap().clone(),
+            }),
+            Some(("uninstall-launcher", sub)) => Some(Action::UninstallLauncher {
+                entry: sub.get_one::<String>("entry").unwrap().clone(),
+            }),
+            Some(("setup", sub)) => Some(Action::Setup {
+                user: sub
+                    .get_one::<String>("user")
+                    .or(matches.get_one::<String>("user"))
+                    .unwrap()
+                    .clone(),
+                group: sub.get_one::<String>("group").unwrap().clone(),
+                dry_run: sub.get_flag("dry-run"),
+            }),
+            Some(("doctor", sub)) => Some(Action::Doctor { json: sub.get_flag("json") }),
+            Some(("prepare", sub)) => Some(Action::Prepare {
+                print_env: sub.get_flag("print-env"),
+                format: match sub.get_one::<String>("format").unwrap().as_str() {
+                    "json" => EnvFormat::Json,
+                    "env" => EnvFormat::Env,
+                    _ => EnvFormat::Sh,
+                },
+            }),
+            Some(("history", _)) => Some(Action::History),
+            Some(("again", sub)) => {
+                Some(Action::Again { num: sub.get_one::<usize>("num").copied() })
+            }
+            Some(("askpass", sub)) => {
+                Some(Action::Askpass { prompt: sub.get_one::<String>("prompt").cloned() })
+            }
+            Some(("relay", sub)) => Some(Action::Relay {
+                parent: *sub.get_one::<u32>("parent").unwrap(),
+                dir: sub.get_one::<PathBuf>("dir").unwrap().clone(),
+                sockets: sub
+                    .get_many("sockets")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+            }),
+            Some(("revoke", sub)) => Some(Action::Revoke {
+                parent: *sub.get_one::<u32>("parent").unwrap(),
+                uid: *sub.get_one::<u32>("uid").unwrap(),
+                acls: sub.get_many("acls").unwrap_or_default().cloned().collect(),
+            }),
+            Some(("notify-bridge", sub)) => Some(Action::NotifyBridge {
+                parent: *sub.get_one::<u32>("parent").unwrap(),
+                prefix: sub.get_one::<String>("prefix").cloned(),
+                socket: sub.get_one::<PathBuf>("socket").unwrap().clone(),
+            }),
+            Some(("notify-proxy", sub)) => Some(Action::NotifyProxy {
+                socket: sub.get_one::<PathBuf>("socket").unwrap().clone(),
+            }),
+            Some(("open-listener", sub)) => Some(Action::OpenListener {
+                parent: *sub.get_one::<u32>("parent").unwrap(),
+                user: sub.get_one::<String>("user").unwrap().clone(),
+                socket: sub.get_one::<PathBuf>("socket").unwrap().clone(),
+            }),
+            Some(("device-acl", sub)) => Some(Action::DeviceAcl {
+                user: sub.get_one::<String>("user").unwrap().clone(),
+                revoke: sub.get_flag("revoke"),
+                paths: sub.get_many("paths").unwrap_or_default().cloned().collect(),
+            }),
+            Some(("open-url", sub)) => Some(Action::OpenUrl {
+                args: sub.get_many("args").unwrap_or_default().cloned().collect(),
+            }),
+            _ => None,
+        },
         user: matches.get_one::<String>("user").unwrap().clone(),
         command: matches
             .get_many("command")
@@ -86,7 +694,53 @@ pub fn parse_args<T: Into<OsString> + Clone>(args: impl IntoIterator<Item = T>)
             2 => Level::Debug,
             _ => Level::Trace,
         },
+        message_format: match matches
+            .get_one::<String>("message-format")
+            .unwrap()
+            .as_str()
+        {
+            "json" => MessageFormat::Json,
+            _ => MessageFormat::Human,
+        },
         old_xhost: matches.get_flag("old-xhost"),
+        share: matches
+            .get_many("share")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        unshare: matches
+            .get_many("unshare")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        device: matches
+            .get_many("device")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        list_shares: matches.get_flag("list-shares"),
+        desktop_file: matches.get_one::<String>("desktop-file").cloned(),
+        flatpak: matches.get_one::<String>("flatpak").cloned(),
+        machine: matches.get_one::<String>("machine").cloned(),
+        dry_run: matches.get_flag("dry-run"),
+        relay: matches.get_flag("relay"),
+        a11y: matches.get_flag("a11y"),
+        ibus: matches.get_flag("ibus"),
+        ssh_agent: matches.get_flag("ssh-agent"),
+        gpg_agent: matches.get_flag("gpg-agent"),
+        open_links: matches.get_flag("open-links"),
+        notifications: matches
+            .get_one::<String>("notifications")
+            .map(|mode| match mode.as_str() {
+                "prefix" => NotifyMode::Prefix,
+                _ => NotifyMode::Plain,
+            }),
+        grant_files: matches
+            .get_one::<String>("grant-args")
+            .map(|mode| match mode.as_str() {
+                "rw" => ShareMode::ReadWrite,
+                _ => ShareMode::ReadOnly,
+            }),
         method: if matches.get_flag("machinectl") {
             Some(Method::Machinectl)
         } else if matches.get_flag("machinectl-bare") {
@@ -0,0 +1,439 @@
+//! Parsing and launching freedesktop.org `.desktop` entries (`--desktop-file`), and installing
+//! launchers that run applications via ego (`install-launcher`).
+//! <https://specifications.freedesktop.org/desktop-entry-spec/latest/>
+
+use crate::cli::Method;
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::util::have_command;
+use crate::{getenv_optional, getenv_path};
+use log::{debug, warn};
+use std::fmt::Write;
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DesktopEntry {
+    pub location: PathBuf,
+    pub name: String,
+    pub exec: String,
+    pub icon: Option<String>,
+    pub working_dir: Option<String>,
+    pub terminal: bool,
+}
+
+/// Unescape a `string` type value: `\s`, `\n`, `\t`, `\r`, `\\`
+fn unescape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+impl DesktopEntry {
+    /// Parse contents of the `[Desktop Entry]` group. Localized keys (`Name[de]=`) are ignored.
+    pub fn parse(content: &str, location: &Path) -> Result<DesktopEntry, AnyErr> {
+        let mut entry = DesktopEntry { location: location.to_path_buf(), ..Default::default() };
+        let mut in_group = false;
+        let mut found_group = false;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_group = line == "[Desktop Entry]";
+                found_group |= in_group;
+                continue;
+            }
+            if !in_group {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = unescape_value(value.trim());
+            match key.trim() {
+                "Name" => entry.name = value,
+                "Exec" => entry.exec = value,
+                "Icon" => entry.icon = Some(value),
+                "Path" if !value.is_empty() => entry.working_dir = Some(value),
+                "Terminal" => entry.terminal = value == "true",
+                _ => {}
+            }
+        }
+
+        if !found_group {
+            bail!("'{}': missing [Desktop Entry] group", location.display());
+        }
+        if entry.exec.is_empty() {
+            bail!("'{}': missing Exec= key", location.display());
+        }
+        Ok(entry)
+    }
+
+    pub fn load(path: &Path) -> Result<DesktopEntry, AnyErr> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => bail!("'{}': {err}", path.display()),
+        };
+        Self::parse(&content, path)
+    }
+
+    /// Build command line from `Exec=`, expanding field codes with `files`.
+    pub fn expand_exec(&self, files: &[String]) -> Result<Vec<String>, AnyErr> {
+        let args = match shell_words::split(&self.exec) {
+            Ok(args) => args,
+            Err(err) => bail!("'{}': invalid Exec= value: {err}", self.location.display()),
+        };
+        let mut used_files = false;
+        let mut cmd = Vec::new();
+
+        for arg in args {
+            match arg.as_str() {
+                "%F" | "%U" => {
+                    cmd.extend(files.iter().cloned());
+                    used_files = true;
+                }
+                "%i" => {
+                    if let Some(icon) = &self.icon {
+                        cmd.extend(["--icon".to_string(), icon.clone()]);
+                    }
+                }
+                _ => {
+                    let (expanded, used) = self.expand_field_codes(&arg, files);
+                    used_files |= used;
+                    // Codes like `%f` that expand to nothing remove the argument entirely
+                    if !expanded.is_empty() || !arg.starts_with('%') {
+                        cmd.push(expanded);
+                    }
+                }
+            }
+        }
+
+        if !files.is_empty() && !used_files {
+            warn!("Desktop entry does not accept files, ignoring: {}", shell_words::join(files));
+        } else if files.len() > 1 && !self.exec.contains("%F") && !self.exec.contains("%U") {
+            return Err(ErrorWithHint::new(
+                format!("'{}' accepts a single file, got {}", self.location.display(), files.len()),
+                "Launch it once per file".into(),
+            )
+            .into());
+        }
+        Ok(cmd)
+    }
+
+    /// Expand field codes within a single argument. Returns whether `files` were used.
+    fn expand_field_codes(&self, arg: &str, files: &[String]) -> (String, bool) {
+        let mut out = String::new();
+        let mut used_files = false;
+        let mut chars = arg.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('f' | 'u') => {
+                    if let Some(file) = files.first() {
+                        out.push_str(file);
+                    }
+                    used_files = true;
+                }
+                Some('c') => out.push_str(&self.name),
+                Some('k') => out.push_str(&self.location.to_string_lossy()),
+                // Deprecated and unknown codes are removed
+                _ => {}
+            }
+        }
+        (out, used_files)
+    }
+}
+
+/// XDG data dirs in order of precedence (`$XDG_DATA_HOME`, then `$XDG_DATA_DIRS`).
+fn xdg_data_dirs() -> Result<Vec<PathBuf>, AnyErr> {
+    let mut dirs = Vec::new();
+    match getenv_optional("XDG_DATA_HOME")? {
+        Some(dir) if !dir.is_empty() => dirs.push(PathBuf::from(dir)),
+        _ => {
+            if let Some(home) = getenv_optional("HOME")? {
+                dirs.push(PathBuf::from(home).join(".local/share"));
+            }
+        }
+    }
+    let data_dirs = getenv_optional("XDG_DATA_DIRS")?
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    dirs.extend(data_dirs.split(':').map(PathBuf::from));
+    Ok(dirs)
+}
+
+/// Resolve `--desktop-file` argument: a path to `.desktop` file, or an application id such as
+/// `firefox` or `org.gnome.Nautilus.desktop` looked up in XDG data dirs.
+pub fn find_desktop_file(name: &str) -> Result<PathBuf, AnyErr> {
+    let path = Path::new(name);
+    if name.contains('/') || path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+
+    let file_name =
+        if name.ends_with(".desktop") { name.to_string() } else { format!("{name}.desktop") };
+    let dirs = xdg_data_dirs()?;
+    for dir in &dirs {
+        let path = dir.join("applications").join(&file_name);
+        if path.is_file() {
+            debug!("Found desktop entry '{}'", path.display());
+            return Ok(path);
+        }
+    }
+
+    Err(ErrorWithHint::new(
+        format!("Desktop entry '{file_name}' not found"),
+        format!(
+            "Searched in: {}",
+            dirs.iter()
+                .map(|d| d.join("applications").display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    )
+    .into())
+}
+
+/// Terminal emulator command for `Terminal=true` entries.
+fn terminal_command() -> Result<Vec<String>, AnyErr> {
+    if let Some(term) = getenv_optional("TERMINAL")?.filter(|v| !v.is_empty()) {
+        return Ok(vec![term, "-e".into()]);
+    }
+    for term in ["x-terminal-emulator", "xterm"] {
+        if have_command(term) {
+            return Ok(vec![term.into(), "-e".into()]);
+        }
+    }
+    Err(ErrorWithHint::new(
+        "Desktop entry requires a terminal, but none was found".into(),
+        "Set the TERMINAL environment variable or install 'xterm'".into(),
+    )
+    .into())
+}
+
+/// Make relative file argument `file` absolute, because the command runs as another user in
+/// another working directory. URLs are kept as is.
+pub fn absolute_file(file: &str) -> Result<String, AnyErr> {
+    let is_url = file.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if is_url || Path::new(file).is_absolute() {
+        return Ok(file.to_string());
+    }
+    let path = std::path::absolute(file)?;
+    Ok(require_with!(path.to_str(), "Path '{}' is not valid UTF-8", path.display()).to_string())
+}
+
+/// Build the command to run for desktop entry `name`, honouring `Path=` and `Terminal=`.
+pub fn desktop_command(name: &str, files: &[String]) -> Result<Vec<String>, AnyErr> {
+    let entry = DesktopEntry::load(&find_desktop_file(name)?)?;
+    let files = files
+        .iter()
+        .map(|f| absolute_file(f))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut cmd = entry.expand_exec(&files)?;
+
+    if entry.terminal {
+        let mut term = terminal_command()?;
+        term.extend(cmd);
+        cmd = term;
+    }
+    if let Some(dir) = entry.working_dir {
+        // Both sudo and machinectl start in the target user's home, change directory afterwards.
+        let mut wrapper: Vec<String> = vec![
+            "/bin/sh".into(),
+            "-c".into(),
+            "cd \"$0\" && exec \"$@\"".into(),
+            dir,
+        ];
+        wrapper.extend(cmd);
+        cmd = wrapper;
+    }
+
+    debug!("Desktop entry '{}' command: {}", entry.name, shell_words::join(&cmd));
+    Ok(cmd)
+}
+
+/// Directory for user's own desktop entries, e.g. `~/.local/share/applications`
+fn user_applications_dir() -> Result<PathBuf, AnyErr> {
+    let base = match getenv_optional("XDG_DATA_HOME")? {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => getenv_path("HOME")?.join(".local/share"),
+    };
+    Ok(base.join("applications"))
+}
+
+/// Desktop file id (file name without `.desktop`) of an entry. Reverse-DNS ids like
+/// `org.gnome.Nautilus` have no extension.
+pub fn desktop_id(path: &Path) -> Result<String, AnyErr> {
+    let name = path.file_name().and_then(|s| s.to_str());
+    let name = require_with!(name, "'{}': invalid desktop file name", path.display());
+    Ok(name.strip_suffix(".desktop").unwrap_or(name).to_string())
+}
+
+fn launcher_path(source: &Path) -> Result<PathBuf, AnyErr> {
+    Ok(user_applications_dir()?.join(format!("ego-{}.desktop", desktop_id(source)?)))
+}
+
+/// Quote `args` for an `Exec=` value, as the Desktop Entry Specification requires. Arguments with
+/// reserved characters are double-quoted, escaping `"`, `` ` ``, `$` and `\` with a backslash, and
+/// a literal `%` is written as `%%`. Backslashes are then escaped again for the string value.
+pub fn exec_quote(args: &[String]) -> String {
+    const RESERVED: &str = " \t\n\"'\\><~|&;$*?#()`";
+    let mut out = String::new();
+    for arg in args {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        let quote = arg.is_empty() || arg.contains(|c| RESERVED.contains(c));
+        if quote {
+            out.push('"');
+        }
+        for c in arg.chars() {
+            match c {
+                '"' | '`' | '$' | '\\' if quote => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                '%' => out.push_str("%%"),
+                c => out.push(c),
+            }
+        }
+        if quote {
+            out.push('"');
+        }
+    }
+    out.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+}
+
+/// Rewrite desktop entry `content` to launch via `ego_cmd`. `Exec=` lines of all groups (including
+/// desktop actions) are prefixed with the ego command, `Name=` gets a suffix.
+pub fn rewrite_launcher(content: &str, ego_cmd: &[String], user: &str, source: &Path) -> String {
+    let ego_cmd = exec_quote(ego_cmd);
+    let mut out = String::with_capacity(content.len() + 200);
+    let mut in_main_group = false;
+    let mut wm_class = None;
+
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            in_main_group = trimmed.trim_end() == "[Desktop Entry]";
+            out.push_str(line);
+            out.push('\n');
+            if in_main_group {
+                writeln!(out, "X-Ego-User={user}").unwrap();
+                writeln!(out, "X-Ego-Source={}", source.display()).unwrap();
+            }
+            continue;
+        }
+        let (key, value) = match trimmed.split_once('=') {
+            Some((key, value)) => (key.trim_end(), value.trim_start()),
+            None => ("", ""),
+        };
+        match key {
+            "Exec" => {
+                if in_main_group && wm_class.is_none() {
+                    // Window class usually matches the executable name
+                    wm_class = shell_words::split(value)
+                        .ok()
+                        .and_then(|args| args.into_iter().next())
+                        .and_then(|exe| Some(Path::new(&exe).file_name()?.to_str()?.to_string()));
+                }
+                writeln!(out, "Exec={ego_cmd} {value}").unwrap();
+            }
+            _ if in_main_group && (key == "Name" || key.starts_with("Name[")) => {
+                writeln!(out, "{key}={value} ({user})").unwrap();
+            }
+            // D-Bus activation would bypass ego entirely
+            "DBusActivatable" => {}
+            "StartupWMClass" if in_main_group => {
+                wm_class = Some(value.to_string());
+            }
+            _ => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+
+    if let Some(wm_class) = wm_class {
+        let header = "[Desktop Entry]\n";
+        if let Some(pos) = out.find(header) {
+            out.insert_str(pos + header.len(), &format!("StartupWMClass={wm_class}\n"));
+        }
+    }
+    out
+}
+
+/// The `ego` command to use in launchers: plain `ego` if found in `$PATH`, otherwise full path.
+fn ego_executable() -> Result<String, AnyErr> {
+    if have_command("ego") {
+        return Ok("ego".into());
+    }
+    let exe = env::current_exe()?;
+    Ok(require_with!(exe.to_str(), "Ego path '{}' is not valid UTF-8", exe.display()).to_string())
+}
+
+/// Write a copy of desktop entry `name` to `~/.local/share/applications` that launches via ego.
+pub fn install_launcher(name: &str, user: &str, method: Option<Method>) -> Result<(), AnyErr> {
+    let source = find_desktop_file(name)?;
+    let content = match fs::read_to_string(&source) {
+        Ok(content) => content,
+        Err(err) => bail!("'{}': {err}", source.display()),
+    };
+    // Validate before writing anything
+    DesktopEntry::parse(&content, &source)?;
+
+    let mut ego_cmd = vec![ego_executable()?, format!("--user={user}")];
+    ego_cmd.extend(method.map(|m| m.as_flag().to_string()));
+    let content = rewrite_launcher(&content, &ego_cmd, user, &source);
+
+    let target = launcher_path(&source)?;
+    fs::create_dir_all(user_applications_dir()?)?;
+    fs::write(&target, content)?;
+    warn!("Installed launcher '{}'", target.display());
+    Ok(())
+}
+
+/// Remove launcher previously installed with `install_launcher`.
+pub fn uninstall_launcher(name: &str) -> Result<(), AnyErr> {
+    let id = desktop_id(Path::new(name))?;
+    let id = id.strip_prefix("ego-").unwrap_or(&id);
+    let target = user_applications_dir()?.join(format!("ego-{id}.desktop"));
+
+    match fs::remove_file(&target) {
+        Ok(()) => {
+            warn!("Removed launcher '{}'", target.display());
+            Ok(())
+        }
+        Err(err) if err.kind() == NotFound => {
+            bail!("Launcher '{}' not installed", target.display())
+        }
+        Err(err) => bail!("'{}': {err}", target.display()),
+    }
+}
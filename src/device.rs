@@ -0,0 +1,261 @@
+//! Device passthrough (`--device`): grant target user access to device nodes like webcams, game
+//! controllers and sound cards. Device ACLs set by logind only cover the seat user.
+//!
+//! Device nodes are owned by root, so their ACLs are changed by `ego device-acl`, run as root
+//! through sudo. The sudoers rule installed by `ego setup` allows this. The helper only passes on
+//! access to devices of the named classes that the invoking user can already use.
+
+use crate::cli::ShareMode;
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::share::{record_share, shared_paths};
+use crate::util::{run_command, system_ego};
+use crate::{
+    EgoContext, Grant, acl_perm_str, add_file_acl, getenv_optional, print_dry_run, remove_file_acl,
+};
+use log::{debug, warn};
+use nix::libc::uid_t;
+use nix::unistd::{Gid, Group, Uid, User};
+use posix_acl::{ACL_READ, ACL_RWX, ACL_WRITE, PosixACL, Qualifier};
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Named device classes: name, sysfs class dir, and `/dev` dir of the device nodes
+const DEVICE_CLASSES: [(&str, &str, &str); 3] = [
+    ("camera", "/sys/class/video4linux", "/dev"),
+    ("gamepad", "/sys/class/input", "/dev/input"),
+    ("audio", "/sys/class/sound", "/dev/snd"),
+];
+
+/// Whether input device `name` (e.g. `event5`, `js0`) in `sysfs_dir` belongs to a joystick. The
+/// kernel creates a `jsN` node for every joystick-like input device.
+fn is_gamepad(sysfs_dir: &Path, name: &str) -> bool {
+    if name.starts_with("js") {
+        return true;
+    }
+    if !name.starts_with("event") {
+        return false;
+    }
+    fs::read_dir(sysfs_dir.join(name).join("device")).is_ok_and(|entries| {
+        entries
+            .filter_map(Result::ok)
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("js"))
+    })
+}
+
+/// Find device nodes of a named class through sysfs, e.g. `camera` → `/dev/video0`.
+pub fn class_devices(sysfs_dir: &Path, dev_dir: &Path, class: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(sysfs_dir) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| match class {
+            "gamepad" => is_gamepad(sysfs_dir, name),
+            // Sound class also contains entries like `card0` that have no device node
+            _ => true,
+        })
+        .collect();
+    names.sort();
+    names
+        .iter()
+        .map(|name| dev_dir.join(name))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// Resolve `--device` argument: a device class name or path to a device node.
+pub fn resolve_devices(spec: &str) -> Result<Vec<PathBuf>, AnyErr> {
+    if let Some((class, sysfs_dir, dev_dir)) = DEVICE_CLASSES.iter().find(|(c, ..)| *c == spec) {
+        let devices = class_devices(Path::new(sysfs_dir), Path::new(dev_dir), class);
+        if devices.is_empty() {
+            warn!("No {class} devices found");
+        }
+        return Ok(devices);
+    }
+
+    let path = fs::canonicalize(spec).map_err(|err| {
+        let classes: Vec<&str> = DEVICE_CLASSES.iter().map(|(class, ..)| *class).collect();
+        ErrorWithHint::new(
+            format!("Device '{spec}': {err}"),
+            format!("Specify a device node path or one of: {}", classes.join(", ")),
+        )
+    })?;
+    if !is_class_device(&path) {
+        bail!("'{}' is not a camera, gamepad or audio device node", path.display());
+    }
+    Ok(vec![path])
+}
+
+/// Whether `path` is a device node of one of the named classes.
+fn is_class_device(path: &Path) -> bool {
+    DEVICE_CLASSES.iter().any(|(class, sysfs_dir, dev_dir)| {
+        class_devices(Path::new(sysfs_dir), Path::new(dev_dir), class).contains(&path.to_path_buf())
+    })
+}
+
+/// Whether `path` is a character device node in `/dev`.
+pub fn is_device_node(path: &Path) -> bool {
+    path.starts_with("/dev")
+        && path
+            .metadata()
+            .is_ok_and(|meta| meta.file_type().is_char_device())
+}
+
+/// Grant target user read-write access to devices. Devices are recorded like shares, so they can be
+/// listed with `--list-shares` and revoked with `--unshare`.
+pub fn grant_devices(ctx: &EgoContext, specs: &[String]) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let mut paths = Vec::new();
+    for spec in specs {
+        paths.extend(resolve_devices(spec)?);
+    }
+    if paths.is_empty() {
+        return Ok(grant);
+    }
+
+    if Uid::effective().is_root() {
+        for path in &paths {
+            grant.add_acl(ctx, path, ACL_READ | ACL_WRITE)?;
+        }
+    } else {
+        run_device_acl_helper(ctx.dry_run, &ctx.target_user, false, &paths)?;
+        let entry = format!("user:{}:{}", ctx.target_user, acl_perm_str(ACL_READ | ACL_WRITE));
+        grant.resources.extend(
+            paths
+                .iter()
+                .map(|path| format!("acl:{entry}:{}", path.display())),
+        );
+    }
+    for path in &paths {
+        if !ctx.dry_run {
+            record_share(ctx, path, ShareMode::ReadWrite)?;
+        }
+        debug!("Granted device '{}'", path.display());
+    }
+    Ok(grant)
+}
+
+/// Revoke access of target `user` to device `path`, for `--unshare`.
+pub fn revoke_device(path: &Path, user: &str) -> Result<(), AnyErr> {
+    if Uid::effective().is_root() {
+        let uid = require_with!(User::from_name(user)?, "Unknown user '{user}'").uid;
+        return remove_file_acl(path, uid.as_raw());
+    }
+    run_device_acl_helper(false, user, true, &[path.to_path_buf()])
+}
+
+/// Run `ego device-acl` as root through sudo, without asking for a password.
+fn run_device_acl_helper(
+    dry_run: bool,
+    user: &str,
+    revoke: bool,
+    paths: &[PathBuf],
+) -> Result<(), AnyErr> {
+    let ego = system_ego()?;
+    let mut args = vec![
+        "-n".to_string(),
+        ego.to_string_lossy().into_owned(),
+        "device-acl".into(),
+        "--user".into(),
+        user.into(),
+    ];
+    if revoke {
+        args.push("--revoke".into());
+    }
+    args.extend(paths.iter().map(|path| path.to_string_lossy().into_owned()));
+    if dry_run {
+        print_dry_run("command", format_args!("sudo {}", shell_words::join(&args)));
+        return Ok(());
+    }
+    run_command("sudo", &args).map_err(|err| device_error(err.message(), user, paths))?;
+    Ok(())
+}
+
+/// Explain how to allow granting devices when `ego device-acl` could not be run.
+pub fn device_error(message: &str, user: &str, paths: &[PathBuf]) -> AnyErr {
+    let shown: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    let group = paths
+        .first()
+        .and_then(|path| path.metadata().ok())
+        .and_then(|meta| Group::from_gid(Gid::from_raw(meta.gid())).ok().flatten())
+        .filter(|group| group.name != "root");
+    let mut hint = format!(
+        "Run 'sudo ego setup' to allow granting devices, or grant access with: \
+        sudo setfacl -m u:{user}:rw {}",
+        shown.join(" ")
+    );
+    if let Some(group) = group {
+        write!(hint, ", or add user {user} to group '{}'", group.name).unwrap();
+    }
+    ErrorWithHint::new(format!("Could not change device ACLs as root: {message}"), hint).into()
+}
+
+/// Check that device `path` may be passed on by `ego device-acl`: a device node of a named class,
+/// that the invoking user `invoker` can already read and write through its ACL or as its owner.
+pub fn check_device(path: &Path, invoker: uid_t) -> Result<(), AnyErr> {
+    let canonical = fs::canonicalize(path).map_err(|err| format!("'{}': {err}", path.display()))?;
+    if canonical != path || !is_device_node(path) || !is_class_device(path) {
+        bail!("'{}' is not a camera, gamepad or audio device node", path.display());
+    }
+    let meta = path.metadata()?;
+    let acl = PosixACL::read_acl(path)?;
+    let mask = acl.get(Qualifier::Mask).unwrap_or(ACL_RWX);
+    let rw = ACL_READ | ACL_WRITE;
+    let via_acl = acl
+        .get(Qualifier::User(invoker))
+        .is_some_and(|perms| perms & mask & rw == rw);
+    let as_owner = meta.uid() == invoker && (meta.mode() >> 6) & rw == rw;
+    if !via_acl && !as_owner {
+        bail!("'{}': you cannot access this device yourself", path.display());
+    }
+    Ok(())
+}
+
+/// `ego device-acl`: as root, grant target `user` read-write access to device `paths` of the user
+/// who invoked sudo, or revoke it with `revoke`.
+pub fn run_device_acl(user: &str, revoke: bool, paths: &[PathBuf]) -> Result<(), AnyErr> {
+    if !Uid::effective().is_root() {
+        bail!("ego device-acl must be run as root through sudo");
+    }
+    let invoker = require_with!(getenv_optional("SUDO_UID")?, "SUDO_UID is not set");
+    let invoker: uid_t = invoker.parse()?;
+    let target = require_with!(User::from_name(user)?, "Unknown user '{user}'")
+        .uid
+        .as_raw();
+    for path in paths {
+        if revoke {
+            // Access may have moved to another seat user since, removing it is always allowed
+            if !is_device_node(path) {
+                bail!("'{}' is not a device node", path.display());
+            }
+            remove_file_acl(path, target)?;
+        } else {
+            check_device(path, invoker)?;
+            add_file_acl(path, target, ACL_READ | ACL_WRITE)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths to revoke for `--unshare` argument: shared device nodes of a class, otherwise the path.
+pub fn unshare_paths(spec: &Path) -> Result<Vec<PathBuf>, AnyErr> {
+    let class = spec
+        .to_str()
+        .and_then(|name| DEVICE_CLASSES.iter().find(|(c, ..)| *c == name));
+    let Some((class, sysfs_dir, dev_dir)) = class else {
+        return Ok(vec![spec.to_path_buf()]);
+    };
+    let shared = shared_paths()?;
+    let devices: Vec<PathBuf> = class_devices(Path::new(sysfs_dir), Path::new(dev_dir), class)
+        .into_iter()
+        .filter(|path| shared.contains(path))
+        .collect();
+    if devices.is_empty() {
+        bail!("No {class} devices are shared");
+    }
+    Ok(devices)
+}
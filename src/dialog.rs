@@ -0,0 +1,159 @@
+//! Graphical reporting when ego runs without a terminal, e.g. from a desktop launcher.
+
+use crate::ensure_ego_statedir;
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::util::{have_command, run_command};
+use log::{debug, warn};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+const TITLE: &str = "Ego error";
+
+/// Whether there is no terminal to show errors or ask for passwords: stdin and stderr are not
+/// terminals and there is no controlling terminal. Stdout alone is often redirected to a file.
+pub fn no_terminal() -> bool {
+    !(io::stdin().is_terminal()
+        || io::stderr().is_terminal()
+        || fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .is_ok())
+}
+
+/// Whether a graphical session is available to display dialogs in.
+fn have_display() -> bool {
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|key| env::var_os(key).is_some_and(|val| !val.is_empty()))
+}
+
+/// Message text with the hint on a separate paragraph.
+pub fn error_text(message: &str, hint: Option<&str>) -> String {
+    match hint {
+        Some(hint) => format!("{message}\n\nHint: {hint}"),
+        None => message.to_string(),
+    }
+}
+
+/// Commands that can display an error, in order of preference. `notify-send` uses the freedesktop
+/// Notifications interface.
+pub fn error_commands(text: &str) -> Vec<(&'static str, Vec<String>)> {
+    let args = |list: &[&str]| list.iter().map(ToString::to_string).collect::<Vec<_>>();
+    vec![
+        (
+            "notify-send",
+            args(&[
+                "--app-name=ego",
+                "--icon=dialog-error",
+                "--urgency=critical",
+                TITLE,
+                text,
+            ]),
+        ),
+        ("zenity", args(&["--error", "--no-markup", "--title", TITLE, "--text", text])),
+        ("kdialog", args(&["--title", TITLE, "--error", text])),
+    ]
+}
+
+/// Show error with the first available notification or dialog tool. Failures are only reported
+/// at debug level.
+pub fn show_error(message: &str, hint: Option<&str>) {
+    if !have_display() {
+        debug!("No display available, not showing error dialog");
+        return;
+    }
+    let text = error_text(message, hint);
+    for (program, args) in error_commands(&text) {
+        if !have_command(program) {
+            continue;
+        }
+        match run_command(program, &args) {
+            Ok(_) => return,
+            Err(err) => debug!("Showing error with {program} failed: {err}"),
+        }
+    }
+    debug!("No notification or dialog tool available");
+}
+
+/// Commands that ask a yes/no question, in order of preference. Exit status 0 means yes.
+pub fn confirm_commands(text: &str) -> Vec<(&'static str, Vec<String>)> {
+    let args = |list: &[&str]| list.iter().map(ToString::to_string).collect::<Vec<_>>();
+    vec![
+        (
+            "zenity",
+            args(&[
+                "--question",
+                "--no-markup",
+                "--title",
+                "Ego",
+                "--text",
+                text,
+            ]),
+        ),
+        ("kdialog", args(&["--title", "Ego", "--yesno", text])),
+    ]
+}
+
+/// Ask for confirmation with the first available dialog tool. Without one, the answer is no.
+pub fn confirm(text: &str) -> bool {
+    let Some((program, args)) = confirm_commands(text)
+        .into_iter()
+        .find(|(program, _)| have_command(program))
+    else {
+        warn!("No dialog tool found to ask for confirmation, install zenity or kdialog");
+        return false;
+    };
+    run_command(program, &args).is_ok()
+}
+
+/// Commands that can ask for a password and print it to stdout, in order of preference.
+pub fn password_commands(prompt: &str) -> Vec<(&'static str, Vec<String>)> {
+    let args = |list: &[&str]| list.iter().map(ToString::to_string).collect::<Vec<_>>();
+    vec![
+        ("zenity", args(&["--entry", "--hide-text", "--title", "Ego", "--text", prompt])),
+        ("kdialog", args(&["--title", "Ego", "--password", prompt])),
+        ("ssh-askpass", args(&[prompt])),
+    ]
+}
+
+/// `ego askpass`: ask for password with the first available dialog tool and print it to stdout,
+/// as expected from a `SUDO_ASKPASS` program.
+pub fn askpass(prompt: Option<&str>) -> Result<(), AnyErr> {
+    let prompt = prompt.unwrap_or("Password:");
+    let Some((program, args)) = password_commands(prompt)
+        .into_iter()
+        .find(|(program, _)| have_command(program))
+    else {
+        return Err(ErrorWithHint::new(
+            "No password dialog tool found".into(),
+            "Install zenity, kdialog or ssh-askpass".into(),
+        )
+        .into());
+    };
+    // Dialog was cancelled if this fails; do not try other tools.
+    let output = run_command(program, &args)?;
+    let password = output.stdout.strip_suffix(b"\n").unwrap_or(&output.stdout);
+    let mut stdout = io::stdout();
+    stdout.write_all(password)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write a `SUDO_ASKPASS` helper script that runs `ego askpass`. Sudo passes only the prompt as
+/// argument, so the subcommand cannot be given directly.
+pub fn install_askpass() -> Result<PathBuf, AnyErr> {
+    let exe = env::current_exe()?;
+    let exe = require_with!(exe.to_str(), "Ego path '{}' is not valid UTF-8", exe.display());
+    let path = ensure_ego_statedir()?.join("askpass");
+    let content = format!("#!/bin/sh\nexec {} askpass \"$@\"\n", shell_words::quote(exe));
+    if fs::read_to_string(&path).is_ok_and(|old| old == content) {
+        return Ok(path);
+    }
+    fs::write(&path, content)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+    Ok(path)
+}
@@ -0,0 +1,286 @@
+//! `ego doctor`: diagnose common setup problems without launching anything.
+
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::setup::drop_in_name;
+use crate::util::{have_command, json_string, paint, run_command, sd_booted};
+use crate::{EgoContext, get_target_user, get_wayland_socket, getenv_optional, homedir_problem};
+use anstyle::{AnsiColor, Color, Style};
+use log::Level;
+use std::fs;
+use std::path::{Path, PathBuf};
+use xcb::{ConnError, Connection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Unknown,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+            Status::Unknown => "unknown",
+        }
+    }
+
+    fn style(self) -> Style {
+        let color = match self {
+            Status::Pass => AnsiColor::Green,
+            Status::Warn => AnsiColor::Yellow,
+            Status::Fail => AnsiColor::Red,
+            Status::Unknown => AnsiColor::BrightBlack,
+        };
+        Style::new().fg_color(Some(Color::Ansi(color))).bold()
+    }
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub hint: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, message: impl Into<String>) -> Check {
+        Check { name, status, message: message.into(), hint: String::new() }
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Check {
+        self.hint = hint.into();
+        self
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"check\":{},\"status\":{},\"message\":{},\"hint\":{}}}",
+            json_string(self.name),
+            json_string(self.status.as_str()),
+            json_string(&self.message),
+            json_string(&self.hint)
+        )
+    }
+}
+
+fn check_systemd() -> Check {
+    if sd_booted() {
+        Check::new("systemd", Status::Pass, "System was booted with systemd")
+    } else {
+        Check::new("systemd", Status::Warn, "System was not booted with systemd")
+            .with_hint("Only the 'sudo' method is available")
+    }
+}
+
+fn check_commands() -> Vec<Check> {
+    let machinectl = if have_command("machinectl") {
+        Check::new("machinectl", Status::Pass, "machinectl is installed")
+    } else {
+        Check::new("machinectl", Status::Warn, "machinectl is not installed").with_hint(
+            "Install systemd-container for better desktop integration, \
+            otherwise 'sudo' is used",
+        )
+    };
+    let sudo = if have_command("sudo") {
+        Check::new("sudo", Status::Pass, "sudo is installed")
+    } else {
+        Check::new("sudo", Status::Warn, "sudo is not installed")
+    };
+    vec![machinectl, sudo]
+}
+
+/// Find polkit rule files that mention the machinectl host-shell action and `user`.
+fn find_polkit_rule(user: &str) -> Option<PathBuf> {
+    let quoted = format!("\"{user}\"");
+    ["/etc/polkit-1/rules.d", "/usr/share/polkit-1/rules.d"]
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| {
+            fs::read_to_string(path).is_ok_and(|content| {
+                content.contains("org.freedesktop.machine1.host-shell") && content.contains(&quoted)
+            })
+        })
+}
+
+/// Whether `sudo -l` output `listing` has a `NOPASSWD` rule that allows running as `user`, e.g.
+/// `(ego) NOPASSWD: ALL`.
+pub fn sudo_allows(listing: &str, user: &str) -> bool {
+    listing.lines().any(|line| {
+        let Some((runas, rule)) = line
+            .trim()
+            .strip_prefix('(')
+            .and_then(|l| l.split_once(')'))
+        else {
+            return false;
+        };
+        let users = runas.split(':').next().unwrap_or_default();
+        users
+            .split(',')
+            .any(|u| matches!(u.trim(), "ALL") || u.trim() == user)
+            && rule.contains("NOPASSWD:")
+    })
+}
+
+fn check_permissions(user: &str) -> Check {
+    let name = "authorization";
+    let hint = "A password will be asked on every launch. Run 'sudo ego setup' to fix.";
+    if let Some(path) = find_polkit_rule(user) {
+        return Check::new(name, Status::Pass, format!("Polkit rule found: {}", path.display()));
+    }
+    // Only readable by root on most systems
+    let rule = PathBuf::from(format!("/etc/sudoers.d/50_{}", drop_in_name(user)));
+    if rule.exists() {
+        return Check::new(name, Status::Pass, format!("sudoers rule found: {}", rule.display()));
+    }
+    if !have_command("sudo") {
+        return Check::new(name, Status::Warn, "No polkit rule found and sudo is not installed")
+            .with_hint(hint);
+    }
+    match run_command("sudo", &["-n", "-l"].map(String::from)) {
+        Ok(output) if sudo_allows(&String::from_utf8_lossy(&output.stdout), user) => {
+            Check::new(name, Status::Pass, format!("sudo allows running as '{user}'"))
+        }
+        Ok(_) => Check::new(name, Status::Warn, "No polkit rule or NOPASSWD sudoers entry found")
+            .with_hint(hint),
+        // sudo wants a password just to list the rules
+        Err(_) => Check::new(name, Status::Unknown, "Cannot list sudo rules without a password")
+            .with_hint(format!("Run 'sudo -l' and look for '({user}) NOPASSWD: ALL'")),
+    }
+}
+
+fn check_user(user: &str, runtime_dir: Option<&Path>) -> (Vec<Check>, Option<EgoContext>) {
+    let user = match get_target_user(user) {
+        Ok(user) => user,
+        Err(err) => {
+            let check = match err.downcast_ref::<ErrorWithHint>() {
+                Some(err) => Check::new("user", Status::Fail, err.message()).with_hint(err.hint()),
+                None => Check::new("user", Status::Fail, err.to_string()),
+            };
+            return (vec![check], None);
+        }
+    };
+    let mut checks = vec![Check::new(
+        "user",
+        Status::Pass,
+        format!("User '{}' exists (UID {})", user.name, user.uid),
+    )];
+    let ctx = EgoContext {
+        runtime_dir: runtime_dir.map(Path::to_path_buf).unwrap_or_default(),
+        target_user: user.name,
+        target_uid: user.uid.as_raw(),
+        target_user_shell: user.shell,
+        target_user_homedir: user.dir,
+        dry_run: false,
+        relay: false,
+        a11y: false,
+        ibus: false,
+        machine: None,
+    };
+    checks.push(match homedir_problem(&ctx) {
+        None => Check::new("home", Status::Pass, "Home directory is owned by target user"),
+        Some((Level::Info, msg)) => Check::new("home", Status::Pass, msg),
+        Some((_, msg)) => Check::new("home", Status::Warn, msg)
+            .with_hint("Run 'sudo ego setup' or create the home directory manually"),
+    });
+    (checks, Some(ctx))
+}
+
+fn check_x11() -> Result<Check, AnyErr> {
+    if getenv_optional("DISPLAY")?.is_none() {
+        return Ok(Check::new("x11", Status::Pass, "DISPLAY not set, X11 not needed"));
+    }
+    Ok(match Connection::connect(None) {
+        Ok(_) => Check::new("x11", Status::Pass, "Connected to X11 via libxcb"),
+        Err(ConnError::LibrariesNotLoaded) => {
+            Check::new("x11", Status::Fail, "libxcb library could not be loaded")
+                .with_hint("Try installing package that contains library 'libxcb.so'")
+        }
+        Err(err) => Check::new("x11", Status::Fail, format!("Error connecting to X11: {err}")),
+    })
+}
+
+fn check_sockets(ctx: &EgoContext) -> Result<Vec<Check>, AnyErr> {
+    let wayland = match get_wayland_socket(ctx)? {
+        None => Check::new("wayland", Status::Pass, "WAYLAND_DISPLAY not set, Wayland not needed"),
+        Some(path) if path.exists() => {
+            Check::new("wayland", Status::Pass, format!("Socket {} found", path.display()))
+        }
+        Some(path) => {
+            Check::new("wayland", Status::Fail, format!("Socket {} not found", path.display()))
+        }
+    };
+    let pulse_path = ctx.runtime_dir.join("pulse/native");
+    let pulse = if pulse_path.exists() {
+        Check::new("pulseaudio", Status::Pass, format!("Socket {} found", pulse_path.display()))
+    } else {
+        Check::new("pulseaudio", Status::Warn, format!("Socket {} not found", pulse_path.display()))
+            .with_hint("Audio will not work unless PulseAudio or pipewire-pulse is running")
+    };
+    Ok(vec![wayland, pulse])
+}
+
+/// Run all checks for target `user`.
+pub fn run_checks(user: &str) -> Result<Vec<Check>, AnyErr> {
+    let mut checks = vec![check_systemd()];
+    checks.extend(check_commands());
+
+    let runtime_dir = getenv_optional("XDG_RUNTIME_DIR")?.map(PathBuf::from);
+    checks.push(match &runtime_dir {
+        Some(dir) if dir.is_dir() => {
+            Check::new("runtime-dir", Status::Pass, format!("{} exists", dir.display()))
+        }
+        Some(dir) => {
+            Check::new("runtime-dir", Status::Fail, format!("{} is not a directory", dir.display()))
+        }
+        None => Check::new("runtime-dir", Status::Fail, "XDG_RUNTIME_DIR is not set")
+            .with_hint("Ego must be run from within a desktop session"),
+    });
+
+    let (user_checks, ctx) = check_user(user, runtime_dir.as_deref());
+    checks.extend(user_checks);
+    checks.push(check_permissions(user));
+    checks.push(check_x11()?);
+    if let Some(ctx) = ctx.filter(|_| runtime_dir.is_some()) {
+        checks.extend(check_sockets(&ctx)?);
+    }
+    Ok(checks)
+}
+
+/// Print check results as a table (or a JSON array). Fails if any check failed.
+pub fn run_doctor(user: &str, json: bool) -> Result<(), AnyErr> {
+    let checks = run_checks(user)?;
+
+    if json {
+        let items: Vec<String> = checks
+            .iter()
+            .map(|c| format!("  {}", c.to_json()))
+            .collect();
+        println!("[\n{}\n]", items.join(",\n"));
+    } else {
+        for check in &checks {
+            let status = format!("{:<7}", check.status.as_str().to_uppercase());
+            println!(
+                "{}  {:<13} {}",
+                paint(check.status.style(), status),
+                check.name,
+                check.message
+            );
+            if !check.hint.is_empty() {
+                println!("{:22} hint: {}", "", check.hint);
+            }
+        }
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        bail!("{failed} check(s) failed");
+    }
+    Ok(())
+}
@@ -1,5 +1,8 @@
-//! Error handling helpers and the `ErrorWithHint` type for more verbose error messages.
+//! Error handling helpers, the `EgoError` type with exit codes, and the `ErrorWithHint` type for
+//! more verbose error messages.
 
+use crate::dialog;
+use crate::logging::{json_message, json_output};
 use crate::util::paint;
 use anstyle::{AnsiColor, Color, Style};
 use log::error;
@@ -21,9 +24,21 @@ pub struct ErrorWithHint {
 }
 
 impl ErrorWithHint {
+    #[must_use]
     pub fn new(err: String, hint: String) -> ErrorWithHint {
         ErrorWithHint { err, hint }
     }
+
+    /// Error message without the hint
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.err
+    }
+
+    #[must_use]
+    pub fn hint(&self) -> &str {
+        &self.hint
+    }
 }
 
 impl Error for ErrorWithHint {}
@@ -40,6 +55,195 @@ impl fmt::Display for ErrorWithHint {
     }
 }
 
+/// Failure classes of ego. Each maps to a distinct process exit code, see [`EgoError::exit_code`].
+///
+/// The wrapped error is displayed as-is, so hints of an [`ErrorWithHint`] are preserved.
+#[derive(Debug)]
+pub enum EgoError {
+    /// Target user lookup failed (exit code 3)
+    User(AnyErr),
+    /// Invoking user's runtime dir is missing or cannot be prepared (exit code 4)
+    RuntimeDir(AnyErr),
+    /// Granting access to Wayland failed (exit code 5)
+    Wayland(AnyErr),
+    /// Granting access to X11 failed (exit code 6)
+    X11(AnyErr),
+    /// Granting access to PulseAudio failed (exit code 7)
+    Audio(AnyErr),
+    /// The `sudo` or `machinectl` command is not installed (exit code 8)
+    BackendMissing(AnyErr),
+    /// The `sudo` or `machinectl` command could not be started (exit code 9)
+    BackendFailed(AnyErr),
+    /// Sharing files or command arguments failed (exit code 10)
+    Share(AnyErr),
+    /// Granting access to devices failed (exit code 11)
+    Device(AnyErr),
+    /// Forwarding the SSH agent or `gpg-agent` failed (exit code 12)
+    Agent(AnyErr),
+    /// Setting up notifications, link handoff, accessibility or input methods failed (exit code 13)
+    Integration(AnyErr),
+}
+
+impl EgoError {
+    /// Exit code of ego for generic errors. Exit code 2 is used for command line usage errors.
+    pub const EXIT_OTHER: i32 = 1;
+
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EgoError::User(_) => 3,
+            EgoError::RuntimeDir(_) => 4,
+            EgoError::Wayland(_) => 5,
+            EgoError::X11(_) => 6,
+            EgoError::Audio(_) => 7,
+            EgoError::BackendMissing(_) => 8,
+            EgoError::BackendFailed(_) => 9,
+            EgoError::Share(_) => 10,
+            EgoError::Device(_) => 11,
+            EgoError::Agent(_) => 12,
+            EgoError::Integration(_) => 13,
+        }
+    }
+
+    /// Underlying error, without the subsystem prefix
+    #[must_use]
+    pub fn inner(&self) -> &AnyErr {
+        match self {
+            EgoError::User(err)
+            | EgoError::RuntimeDir(err)
+            | EgoError::Wayland(err)
+            | EgoError::X11(err)
+            | EgoError::Audio(err)
+            | EgoError::BackendMissing(err)
+            | EgoError::BackendFailed(err)
+            | EgoError::Share(err)
+            | EgoError::Device(err)
+            | EgoError::Agent(err)
+            | EgoError::Integration(err) => err,
+        }
+    }
+
+    /// Underlying error, dropping the failure class
+    #[must_use]
+    pub fn into_inner(self) -> AnyErr {
+        match self {
+            EgoError::User(err)
+            | EgoError::RuntimeDir(err)
+            | EgoError::Wayland(err)
+            | EgoError::X11(err)
+            | EgoError::Audio(err)
+            | EgoError::BackendMissing(err)
+            | EgoError::BackendFailed(err)
+            | EgoError::Share(err)
+            | EgoError::Device(err)
+            | EgoError::Agent(err)
+            | EgoError::Integration(err) => err,
+        }
+    }
+
+    /// Subsystem name, as reported by `--message-format=json`
+    #[must_use]
+    pub fn subsystem(&self) -> &'static str {
+        match self {
+            EgoError::User(_) => "user",
+            EgoError::RuntimeDir(_) => "runtime-dir",
+            EgoError::Wayland(_) => "wayland",
+            EgoError::X11(_) => "x11",
+            EgoError::Audio(_) => "audio",
+            EgoError::BackendMissing(_) | EgoError::BackendFailed(_) => "backend",
+            EgoError::Share(_) => "share",
+            EgoError::Device(_) => "device",
+            EgoError::Agent(_) => "agent",
+            EgoError::Integration(_) => "integration",
+        }
+    }
+
+    fn prefix(&self) -> Option<&'static str> {
+        match self {
+            EgoError::RuntimeDir(_) => Some("Error preparing runtime dir"),
+            EgoError::Wayland(_) => Some("Error preparing Wayland"),
+            EgoError::X11(_) => Some("Error preparing X11"),
+            EgoError::Audio(_) => Some("Error preparing PulseAudio"),
+            EgoError::Share(_) => Some("Error sharing files"),
+            EgoError::Device(_) => Some("Error granting device access"),
+            EgoError::Agent(_) => Some("Error forwarding agent"),
+            EgoError::Integration(_) => Some("Error setting up desktop integration"),
+            _ => None,
+        }
+    }
+
+    /// Error message without the hint
+    #[must_use]
+    pub fn message(&self) -> String {
+        let inner = self.inner();
+        let message = match inner.downcast_ref::<ErrorWithHint>() {
+            Some(err) => err.message().to_string(),
+            None => inner.to_string(),
+        };
+        match self.prefix() {
+            Some(prefix) => format!("{prefix}: {message}"),
+            None => message,
+        }
+    }
+
+    /// Hint of the underlying [`ErrorWithHint`], if any
+    #[must_use]
+    pub fn hint(&self) -> Option<&str> {
+        self.inner()
+            .downcast_ref::<ErrorWithHint>()
+            .map(ErrorWithHint::hint)
+            .filter(|hint| !hint.is_empty())
+    }
+}
+
+impl Error for EgoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.inner().as_ref())
+    }
+}
+
+impl fmt::Display for EgoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.prefix() {
+            Some(prefix) => write!(f, "{prefix}: {}", self.inner()),
+            None => self.inner().fmt(f),
+        }
+    }
+}
+
+/// Split error into subsystem, message and hint.
+fn error_parts(err: &AnyErr) -> (&'static str, String, Option<&str>) {
+    if let Some(err) = err.downcast_ref::<EgoError>() {
+        (err.subsystem(), err.message(), err.hint())
+    } else if let Some(err) = err.downcast_ref::<ErrorWithHint>() {
+        ("ego", err.message().to_string(), Some(err.hint()).filter(|h| !h.is_empty()))
+    } else {
+        ("ego", err.to_string(), None)
+    }
+}
+
 pub fn print_error(err: &AnyErr) {
-    error!("{err}");
+    if !json_output() {
+        error!("{err}");
+        return;
+    }
+    let (subsystem, message, hint) = error_parts(err);
+    eprintln!("{}", json_message("error", subsystem, &message, hint));
+}
+
+/// Print fatal error. Without a terminal (e.g. started from a desktop launcher), also show it as
+/// a desktop notification or dialog, because the output would go unseen.
+pub fn report_error(err: &AnyErr) {
+    print_error(err);
+    if !json_output() && dialog::no_terminal() {
+        let (_, message, hint) = error_parts(err);
+        dialog::show_error(&message, hint);
+    }
+}
+
+/// Process exit code for `err`: specific to the failure class for [`EgoError`], otherwise 1.
+#[must_use]
+pub fn exit_code(err: &AnyErr) -> i32 {
+    err.downcast_ref::<EgoError>()
+        .map_or(EgoError::EXIT_OTHER, EgoError::exit_code)
 }
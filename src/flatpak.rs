@@ -0,0 +1,120 @@
+//! Flatpak mode (`--flatpak APP_ID`): run a Flatpak application installed for the target user or
+//! system-wide, with a session bus and portals for the target user.
+
+use crate::cli::Method;
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::util::have_command;
+use crate::{EgoContext, wrap_command};
+use log::{debug, warn};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// System-wide Flatpak installation
+const SYSTEM_INSTALLATION: &str = "/var/lib/flatpak";
+
+/// Validate Flatpak application id, e.g. `org.mozilla.firefox`.
+pub fn validate_app_id(app_id: &str) -> Result<(), AnyErr> {
+    let elements: Vec<&str> = app_id.split('.').collect();
+    let valid = elements.len() >= 2
+        && elements.iter().all(|element| {
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        });
+    if !valid {
+        bail!("Invalid Flatpak application id '{app_id}'");
+    }
+    Ok(())
+}
+
+/// Installations the target user can run applications from: their own and the system-wide one.
+pub fn installations(homedir: &Path) -> Vec<PathBuf> {
+    vec![
+        homedir.join(".local/share/flatpak"),
+        PathBuf::from(SYSTEM_INSTALLATION),
+    ]
+}
+
+/// Check that `app_id` is installed in one of `installations`. Installations we cannot read, like
+/// the target user's in a private home directory, are assumed to have it.
+pub fn check_installed(app_id: &str, installations: &[PathBuf]) -> Result<(), AnyErr> {
+    let mut unknown = Vec::new();
+    for installation in installations {
+        let path = installation.join("app").join(app_id).join("current");
+        match path.try_exists() {
+            Ok(true) => {
+                debug!("Flatpak {app_id} found in '{}'", installation.display());
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => unknown.push(installation),
+            Err(err) => bail!("Checking '{}': {err}", path.display()),
+        }
+    }
+    if let Some(installation) = unknown.first() {
+        warn!(
+            "Cannot check whether Flatpak {app_id} is installed in '{}', trying anyway",
+            installation.display()
+        );
+        return Ok(());
+    }
+    Err(ErrorWithHint::new(
+        format!("Flatpak application {app_id} is not installed for target user or system-wide"),
+        format!("Install it with: flatpak install --system {app_id}"),
+    )
+    .into())
+}
+
+/// Command that runs Flatpak application `app_id` with `args`.
+pub fn flatpak_command(
+    ctx: &EgoContext,
+    app_id: &str,
+    args: &[String],
+) -> Result<Vec<String>, AnyErr> {
+    if !have_command("flatpak") {
+        return Err(ErrorWithHint::new(
+            "flatpak is not installed".into(),
+            "Try installing package that contains command 'flatpak'".into(),
+        )
+        .into());
+    }
+    validate_app_id(app_id)?;
+    check_installed(app_id, &installations(&ctx.target_user_homedir))?;
+
+    let mut command = vec!["flatpak".to_string(), "run".into(), app_id.into()];
+    command.extend(args.iter().cloned());
+    Ok(command)
+}
+
+/// Shell script that makes sure the target user has a session bus: the user's bus in the runtime
+/// dir, otherwise a private one with `dbus-run-session`. Portals are started by D-Bus activation,
+/// so the activation environment gets our display and audio variables.
+pub fn session_bus_script(env_names: &[&str]) -> String {
+    format!(
+        "if [ -S \"$XDG_RUNTIME_DIR/bus\" ]; then \
+        export DBUS_SESSION_BUS_ADDRESS=\"unix:path=$XDG_RUNTIME_DIR/bus\"; \
+        dbus-update-activation-environment --systemd {}; \
+        elif [ -z \"$DBUS_SESSION_BUS_ADDRESS\" ]; then exec dbus-run-session -- \"$@\"; fi",
+        shell_words::join(env_names)
+    )
+}
+
+/// Wrap `command` to run with the target user's session bus.
+pub fn wrap_session_bus(
+    ctx: &EgoContext,
+    method: Method,
+    env: &[String],
+    command: Vec<String>,
+) -> Result<Vec<String>, AnyErr> {
+    if method == Method::MachinectlBare {
+        warn!("--machinectl-bare skips portal setup, Flatpak applications may not work");
+    }
+    let env_names: Vec<&str> = env
+        .iter()
+        .filter_map(|v| v.split_once('='))
+        .map(|(k, _)| k)
+        .collect();
+    wrap_command(ctx, &[session_bus_script(&env_names)], command)
+}
@@ -0,0 +1,221 @@
+//! Reverse handoff (`--open-links`): links opened by target user's applications are opened with
+//! the invoking user's own browser, after confirmation.
+//!
+//! Target user gets an `xdg-open` shim (also used as `BROWSER`) that runs `ego open-url`. It sends
+//! the link to `ego open-listener`, which runs in the invoking user's session. Other arguments,
+//! like local files, are passed on to the target user's own `xdg-open`.
+
+use crate::dialog::confirm;
+use crate::errors::AnyErr;
+use crate::relay::{create_relay_dir, spawn_helper, wait_for_parent};
+use crate::util::{exec_command, system_ego};
+use crate::{EgoContext, Grant, getenv_optional, print_dry_run};
+use log::{debug, info};
+use posix_acl::{ACL_EXECUTE, ACL_READ, ACL_WRITE};
+use std::fs::{self, DirBuilder};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use std::{env, process, thread};
+
+/// Name of the listener socket in the relay dir
+const OPEN_SOCKET: &str = "open";
+/// Environment variable that tells `ego open-url` where the listener is
+const SOCKET_VAR: &str = "EGO_OPEN_SOCKET";
+/// Only links with these schemes are opened in the invoking user's session
+const ALLOWED_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+const MAX_URL_LEN: usize = 8192;
+/// Clients that do not send a link in time are disconnected
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decode `%XX` escapes in a URL component. Invalid escapes are kept as-is.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                out.push(byte);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether `mailto` link parameters ask the mail client to attach a file, e.g.
+/// `mailto:a@example.com?attach=/home/me/.ssh/id_ed25519`.
+fn has_attachment(rest: &str) -> bool {
+    let Some((_, query)) = rest.split_once('?') else {
+        return false;
+    };
+    query.split('&').any(|param| {
+        let name = percent_decode(param.split('=').next().unwrap_or_default());
+        matches!(name.trim().to_ascii_lowercase().as_str(), "attach" | "attachment")
+    })
+}
+
+/// Whether `url` may be opened in the invoking user's session.
+pub fn is_handoff_url(url: &str) -> bool {
+    let Some((scheme, rest)) = url.split_once(':') else {
+        return false;
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    ALLOWED_SCHEMES.contains(&scheme.as_str())
+        && !rest.is_empty()
+        && url.len() <= MAX_URL_LEN
+        && !url.chars().any(char::is_control)
+        && !(scheme == "mailto" && has_attachment(rest))
+}
+
+/// Shim script for target user, used as `xdg-open` and `BROWSER`.
+pub fn shim_script(exe: &str) -> String {
+    format!("#!/bin/sh\nexec {} open-url \"$@\"\n", shell_words::quote(exe))
+}
+
+/// Read a link from target user's `stream`, ask for confirmation and open it. Only one
+/// confirmation dialog is shown at a time, so that a misbehaving application cannot open a flood
+/// of dialogs.
+fn handle_request(stream: &UnixStream, user: &str, dialog: &Mutex<()>) -> Result<(), AnyErr> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_URL_LEN as u64 + 1)).read_line(&mut line)?;
+    let url = line.trim_end_matches('\n');
+    if !is_handoff_url(url) {
+        writeln!(&*stream, "invalid")?;
+        bail!("Refused to open invalid link");
+    }
+    let question = format!("User {user} wants to open a link in your browser:\n\n{url}");
+    let _dialog = dialog.lock().unwrap_or_else(PoisonError::into_inner);
+    if !confirm(&question) {
+        writeln!(&*stream, "denied")?;
+        return Ok(());
+    }
+    Command::new("xdg-open")
+        .arg(url)
+        .stdin(Stdio::null())
+        .spawn()?;
+    writeln!(&*stream, "ok")?;
+    Ok(())
+}
+
+/// `ego open-listener`: handle links on `socket` until process `parent` exits.
+pub fn run_open_listener(parent: u32, user: &str, socket: &Path) -> Result<(), AnyErr> {
+    let listener = UnixListener::bind(socket)?;
+    let user = Arc::new(user.to_string());
+    let dialog = Arc::new(Mutex::new(()));
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(Result::ok) {
+            let (user, dialog) = (user.clone(), dialog.clone());
+            thread::spawn(move || {
+                if let Err(err) = handle_request(&stream, &user, &dialog) {
+                    debug!("Open listener: {err}");
+                }
+            });
+        }
+    });
+
+    wait_for_parent(parent);
+    debug!("Parent exited, stopping open listener");
+    fs::remove_file(socket)?;
+    // Removed by the relay instead, if it also uses the dir
+    if let Some(dir) = socket.parent() {
+        let _ = fs::remove_dir_all(dir.join("bin"));
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// Run target user's own `xdg-open`, skipping the shim dir in `PATH`.
+fn exec_xdg_open(shim_dir: &Path, args: &[String]) -> Result<(), AnyErr> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    let program = env::split_paths(&path)
+        .filter(|dir| dir != shim_dir)
+        .map(|dir| dir.join("xdg-open"))
+        .find(|program| program.is_file());
+    let program = require_with!(program, "xdg-open not found");
+    let program =
+        require_with!(program.to_str(), "Path '{}' is not valid UTF-8", program.display());
+    Ok(exec_command(program, args)?)
+}
+
+/// `ego open-url`: send link to the listener in the invoking user's session.
+pub fn open_url(args: &[String]) -> Result<(), AnyErr> {
+    let socket = require_with!(getenv_optional(SOCKET_VAR)?, "{SOCKET_VAR} is not set");
+    let socket = PathBuf::from(socket);
+    let url = match args {
+        [url] if is_handoff_url(url) => url,
+        _ => {
+            info!("Not a link, opening as user: {}", shell_words::join(args));
+            let shim_dir = socket.with_file_name("bin");
+            return exec_xdg_open(&shim_dir, args);
+        }
+    };
+
+    let mut stream = UnixStream::connect(&socket)?;
+    writeln!(stream, "{url}")?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    match reply.trim() {
+        "ok" => Ok(()),
+        "denied" => bail!("Opening '{url}' was denied"),
+        _ => bail!("Opening '{url}' failed"),
+    }
+}
+
+/// Start the listener and install the `xdg-open` shim for target user.
+pub fn prepare_open_links(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let dir = create_relay_dir(ctx, &mut grant)?;
+    let socket = dir.join(OPEN_SOCKET);
+    let socket_arg =
+        require_with!(socket.to_str(), "Path '{}' is not valid UTF-8", socket.display());
+    let shim_dir = dir.join("bin");
+    let shim = shim_dir.join("xdg-open");
+    let exe = system_ego()?;
+    let exe = require_with!(exe.to_str(), "Ego path '{}' is not valid UTF-8", exe.display());
+
+    let args = [
+        "open-listener",
+        "--parent",
+        &process::id().to_string(),
+        "--user",
+        &ctx.target_user,
+        socket_arg,
+    ]
+    .map(String::from);
+    if ctx.dry_run {
+        print_dry_run("open-links", format!("ego {}", shell_words::join(&args)));
+        print_dry_run("file", format!("write {}", shim.display()));
+    } else {
+        DirBuilder::new().mode(0o700).create(&shim_dir)?;
+        fs::write(&shim, shim_script(exe))?;
+        fs::set_permissions(&shim, fs::Permissions::from_mode(0o700))?;
+        spawn_helper(&args, std::slice::from_ref(&socket))?;
+    }
+    grant.add_acl(ctx, &socket, ACL_READ | ACL_WRITE)?;
+    grant.add_acl(ctx, &shim_dir, ACL_EXECUTE)?;
+    grant.add_acl(ctx, &shim, ACL_READ | ACL_EXECUTE)?;
+
+    grant.env.push(format!("{SOCKET_VAR}={socket_arg}"));
+    grant.env.push(format!("BROWSER={}", shim.display()));
+    let shim_dir = shim_dir.display().to_string();
+    grant
+        .setup
+        .push(format!("export PATH={}:\"$PATH\"", shell_words::quote(&shim_dir)));
+    debug!("Link handoff '{}' configured", socket.display());
+    Ok(grant)
+}
@@ -0,0 +1,151 @@
+//! Launch history in `$XDG_STATE_HOME/ego/history` (`ego history`, `ego again`).
+
+use crate::ensure_ego_statedir;
+use crate::errors::AnyErr;
+use crate::util::{escape_field, unescape_field};
+use log::debug;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind::NotFound;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Older entries are discarded
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Seconds since Unix epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub method: String,
+    /// Working directory of the launch
+    pub cwd: Option<PathBuf>,
+    /// Ego command line arguments, excluding the program name
+    pub args: Vec<String>,
+}
+
+impl HistoryEntry {
+    /// Entries are stored as tab-separated lines: `timestamp user method cwd args`, with tabs
+    /// and newlines escaped.
+    pub fn parse(line: &str) -> Option<HistoryEntry> {
+        let parts: Vec<&str> = line.splitn(5, '\t').collect();
+        let [timestamp, user, method, cwd, args] = parts[..] else {
+            return None;
+        };
+        let cwd = Some(unescape_field(cwd)).filter(|c| !c.is_empty());
+        Some(HistoryEntry {
+            timestamp: timestamp.parse().ok()?,
+            user: user.to_string(),
+            method: method.to_string(),
+            cwd: cwd.map(|c| PathBuf::from(OsString::from_vec(c))),
+            args: shell_words::split(&String::from_utf8_lossy(&unescape_field(args))).ok()?,
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let cwd = self
+            .cwd
+            .as_ref()
+            .map_or(&[][..], |c| c.as_os_str().as_bytes());
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.user,
+            self.method,
+            escape_field(cwd),
+            escape_field(shell_words::join(&self.args).as_bytes())
+        )
+    }
+}
+
+/// Format Unix timestamp as UTC date and time, e.g. `2026-01-08 12:34:56`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    // Civil from days algorithm: https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn history_file() -> Result<PathBuf, AnyErr> {
+    Ok(ensure_ego_statedir()?.join("history"))
+}
+
+pub fn load_history() -> Result<Vec<HistoryEntry>, AnyErr> {
+    let path = history_file()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == NotFound => return Ok(vec![]),
+        Err(err) => bail!("'{}': {err}", path.display()),
+    };
+    Ok(content.lines().filter_map(HistoryEntry::parse).collect())
+}
+
+/// Append a launch to history. Errors are only reported at debug level.
+pub fn record_launch(user: &str, method: &str, args: &[String]) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let entry = HistoryEntry {
+        timestamp,
+        user: user.to_string(),
+        method: method.to_string(),
+        cwd: env::current_dir().ok(),
+        args: args.to_vec(),
+    };
+
+    let result = load_history().and_then(|mut entries| {
+        entries.push(entry);
+        let skip = entries.len().saturating_sub(MAX_ENTRIES);
+        let content: String = entries[skip..].iter().map(|e| e.to_line() + "\n").collect();
+        fs::write(history_file()?, content)?;
+        Ok(())
+    });
+    if let Err(err) = result {
+        debug!("Could not write history: {err}");
+    }
+}
+
+/// Print numbered history, oldest first.
+pub fn list_history() -> Result<(), AnyErr> {
+    for (num, entry) in load_history()?.iter().enumerate() {
+        println!(
+            "{:>4}  {}  {:<8} {:<15} {}",
+            num + 1,
+            format_timestamp(entry.timestamp),
+            entry.user,
+            entry.method,
+            shell_words::join(&entry.args)
+        );
+    }
+    Ok(())
+}
+
+/// Get history entry `num` (as numbered by `ego history`), or the latest entry.
+pub fn history_entry(num: Option<usize>) -> Result<HistoryEntry, AnyErr> {
+    let mut entries = load_history()?;
+    if entries.is_empty() {
+        bail!("History is empty");
+    }
+    let index = match num {
+        None => entries.len() - 1,
+        Some(num) if (1..=entries.len()).contains(&num) => num - 1,
+        Some(num) => bail!("History entry {num} not found (there are {} entries)", entries.len()),
+    };
+    Ok(entries.swap_remove(index))
+}
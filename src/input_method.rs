@@ -0,0 +1,103 @@
+//! Input method integration: let target user's applications use the invoking user's `IBus` or
+//! Fcitx5 daemon, so that e.g. CJK text can be typed.
+//!
+//! On Wayland, native applications use the compositor's text-input protocol and need no toolkit
+//! input method modules. On X11 (and for `XWayland` applications), input methods are used through
+//! their XIM frontend, which goes through the X server that the target user already has access to.
+//! With `--ibus`, `IBus` is reached through its own D-Bus daemon instead, which is relayed like the
+//! accessibility bus. Fcitx5 listens on the invoking user's session bus, which the target user
+//! cannot reach, so it always uses XIM.
+
+use crate::errors::AnyErr;
+use crate::relay::{IBUS_BUS, RelaySocket, parse_bus_address};
+use crate::util::{have_command, run_command};
+use crate::{EgoContext, getenv_optional};
+use log::{debug, warn};
+
+/// Input method environment variables, as seen by toolkits
+const IM_VARS: [&str; 3] = ["GTK_IM_MODULE", "QT_IM_MODULE", "XMODIFIERS"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputMethod {
+    IBus,
+    Fcitx,
+}
+
+/// Detect active input method from `GTK_IM_MODULE`, `QT_IM_MODULE` and `XMODIFIERS` values
+/// (`XMODIFIERS` is of the form `@im=ibus`).
+pub fn detect_input_method(values: &[&str]) -> Option<InputMethod> {
+    values.iter().find_map(|value| {
+        let value = value.strip_prefix("@im=").unwrap_or(value);
+        if value.starts_with("ibus") {
+            Some(InputMethod::IBus)
+        } else if value.starts_with("fcitx") {
+            Some(InputMethod::Fcitx)
+        } else {
+            None
+        }
+    })
+}
+
+/// Environment for target user. Toolkit modules are only used if the daemon's bus is relayed,
+/// otherwise XIM. On Wayland, toolkit modules are disabled in favour of the text-input protocol;
+/// `XMODIFIERS` is still passed for `XWayland` applications.
+pub fn input_method_env(im: InputMethod, wayland: bool, relayed: bool) -> Vec<String> {
+    let xim = match im {
+        InputMethod::IBus => "ibus",
+        InputMethod::Fcitx => "fcitx",
+    };
+    let module = if relayed { xim } else { "xim" };
+    let mut env = vec![format!("XMODIFIERS=@im={xim}")];
+    if !wayland {
+        env.push(format!("GTK_IM_MODULE={module}"));
+        env.push(format!("QT_IM_MODULE={module}"));
+    }
+    env
+}
+
+/// Find the `IBus` daemon's D-Bus socket from `IBUS_ADDRESS` or `ibus address`.
+fn find_ibus_socket() -> Result<RelaySocket, AnyErr> {
+    let address = if let Some(address) = getenv_optional("IBUS_ADDRESS")? {
+        address
+    } else {
+        if !have_command("ibus") {
+            bail!("ibus command not found");
+        }
+        let output = run_command("ibus", &["address".to_string()])?;
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    let Some(target) = parse_bus_address(&address) else {
+        bail!("IBus address '{address}' is not supported");
+    };
+    Ok(RelaySocket { name: IBUS_BUS, target, env_prefix: "IBUS_ADDRESS=unix:path=" })
+}
+
+/// Detect the invoking user's input method. Returns environment for the target user and the `IBus`
+/// socket to relay, if requested with `--ibus`. Failures only cause a warning.
+pub fn prepare_input_method(
+    ctx: &EgoContext,
+    wayland: bool,
+) -> Result<(Vec<String>, Option<RelaySocket>), AnyErr> {
+    let mut values = Vec::new();
+    for key in IM_VARS {
+        values.extend(getenv_optional(key)?);
+    }
+    let values: Vec<&str> = values.iter().map(String::as_str).collect();
+    let Some(im) = detect_input_method(&values) else {
+        debug!("Input method: no IBus or Fcitx5 configured, skipping");
+        return Ok((vec![], None));
+    };
+
+    // Fcitx5 toolkit modules need the session bus, it always uses XIM
+    let socket = match im {
+        InputMethod::IBus if !wayland && ctx.ibus => find_ibus_socket()
+            .inspect_err(|err| warn!("Input method: IBus bus not available, using XIM: {err}"))
+            .ok(),
+        _ => None,
+    };
+    if socket.is_some() {
+        warn!("--ibus: user {} can see what you type in your own applications", ctx.target_user);
+    }
+    debug!("Input method {im:?} configured (Wayland: {wayland})");
+    Ok((input_method_env(im, wayland, socket.is_some()), socket))
+}
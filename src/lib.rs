@@ -0,0 +1,1155 @@
+//! Alter Ego: run Linux desktop applications under a different local user.
+//!
+//! Besides the `ego` command line tool, the core operations are available as a library:
+//!
+//! ```no_run
+//! use ego::{EgoContext, Method, launch, prepare_session, start_relay};
+//!
+//! # fn main() -> Result<(), ego::errors::AnyErr> {
+//! let ctx = EgoContext::builder("ego").build()?;
+//! let mut grant = prepare_session(&ctx, false)?;
+//! start_relay(&ctx, &mut grant)?;
+//! println!("Granted: {:?}", grant.resources);
+//! launch(&ctx, Method::Machinectl, grant.env, vec!["xdg-open".into(), ".".into()])?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![warn(clippy::pedantic)]
+#![warn(clippy::cargo)]
+#![allow(clippy::module_name_repetitions)]
+#![allow(clippy::multiple_crate_versions)]
+
+#[macro_use]
+extern crate simple_error;
+
+use crate::cli::{Action, Args, EnvFormat, NotifyMode, parse_args};
+use crate::errors::{AnyErr, EgoError, ErrorWithHint};
+use crate::relay::RelaySocket;
+use crate::util::{exec_command_env, have_command, json_string, sd_booted};
+use crate::x11::{x11_add_acl_with_fallback, x11_xhost_add_acl};
+use log::{Level, debug, info, log, warn};
+use nix::libc::uid_t;
+use nix::unistd::{Uid, User};
+use posix_acl::{ACL_EXECUTE, ACL_READ, ACL_RWX, ACL_WRITE, ACLError, PosixACL, Qualifier};
+use simple_error::SimpleError;
+use std::env::VarError;
+use std::ffi::OsStr;
+use std::fmt::{Display, Write};
+use std::fs::{DirBuilder, Metadata};
+use std::io::ErrorKind::{NotFound, PermissionDenied};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs, iter, mem};
+
+mod accessibility;
+mod agent;
+mod cli;
+mod desktop;
+mod device;
+mod dialog;
+mod doctor;
+pub mod errors;
+mod flatpak;
+mod handoff;
+mod history;
+mod input_method;
+mod logging;
+mod machine;
+mod notify;
+mod relay;
+mod setup;
+mod share;
+#[cfg(test)]
+mod tests;
+mod util;
+mod x11;
+
+pub use crate::cli::{Method, ShareMode, ShareSpec};
+
+/// Target user and settings for granting access. Create with [`EgoContext::builder`].
+#[derive(Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct EgoContext {
+    pub(crate) runtime_dir: PathBuf,
+    pub(crate) target_user: String,
+    pub(crate) target_uid: uid_t,
+    pub(crate) target_user_shell: PathBuf,
+    pub(crate) target_user_homedir: PathBuf,
+    /// Only print planned changes (`--dry-run`)
+    pub(crate) dry_run: bool,
+    /// Relay sockets instead of granting access to runtime dir (`--relay`)
+    pub(crate) relay: bool,
+    /// Relay the accessibility bus (`--a11y`)
+    pub(crate) a11y: bool,
+    /// Relay the `IBus` input method bus (`--ibus`)
+    pub(crate) ibus: bool,
+    /// Machine to launch in with machinectl, instead of the host (`--machine`)
+    pub(crate) machine: Option<String>,
+}
+
+impl EgoContext {
+    /// Start building a context for target user `username`.
+    #[must_use]
+    pub fn builder(username: &str) -> EgoContextBuilder {
+        EgoContextBuilder {
+            username: username.to_string(),
+            runtime_dir: None,
+            dry_run: false,
+            relay: false,
+            a11y: false,
+            ibus: false,
+            machine: None,
+        }
+    }
+
+    #[must_use]
+    pub fn target_user(&self) -> &str {
+        &self.target_user
+    }
+
+    #[must_use]
+    pub fn target_uid(&self) -> uid_t {
+        self.target_uid
+    }
+
+    /// Runtime dir of the invoking user, e.g. `/run/user/1000`
+    #[must_use]
+    pub fn runtime_dir(&self) -> &Path {
+        &self.runtime_dir
+    }
+}
+
+/// Builder for [`EgoContext`]
+#[derive(Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct EgoContextBuilder {
+    username: String,
+    runtime_dir: Option<PathBuf>,
+    dry_run: bool,
+    relay: bool,
+    a11y: bool,
+    ibus: bool,
+    machine: Option<String>,
+}
+
+impl EgoContextBuilder {
+    /// Runtime dir of the invoking user (default: `$XDG_RUNTIME_DIR`)
+    #[must_use]
+    pub fn runtime_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.runtime_dir = Some(path.into());
+        self
+    }
+
+    /// Only print planned changes instead of making them.
+    #[must_use]
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Relay display and audio sockets through a private directory, instead of granting the target
+    /// user access to the runtime dir.
+    #[must_use]
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Relay the invoking user's accessibility (AT-SPI) bus, if assistive technologies are enabled.
+    /// The target user's applications can then read and control all accessible applications of
+    /// the invoking user.
+    #[must_use]
+    pub fn a11y(mut self, a11y: bool) -> Self {
+        self.a11y = a11y;
+        self
+    }
+
+    /// Relay the invoking user's `IBus` input method bus on X11, instead of using its XIM frontend.
+    /// The target user's applications can then see what the invoking user types.
+    #[must_use]
+    pub fn ibus(mut self, ibus: bool) -> Self {
+        self.ibus = ibus;
+        self
+    }
+
+    /// Launch in another machine, e.g. a systemd-nspawn container, with the machinectl method.
+    #[must_use]
+    pub fn machine(mut self, machine: Option<&str>) -> Self {
+        self.machine = machine.map(String::from);
+        self
+    }
+
+    /// Look up the target user and build the context.
+    ///
+    /// # Errors
+    /// [`EgoError::User`] if the target user does not exist, [`EgoError::RuntimeDir`] if runtime
+    /// dir was not given and `XDG_RUNTIME_DIR` is unset.
+    pub fn build(self) -> Result<EgoContext, EgoError> {
+        let user = get_target_user(&self.username).map_err(EgoError::User)?;
+        debug!("Found user '{}' UID {} shell '{}'", user.name, user.uid, user.shell.display());
+        let runtime_dir = match self.runtime_dir {
+            Some(path) => path,
+            None => {
+                getenv_path("XDG_RUNTIME_DIR").map_err(|err| EgoError::RuntimeDir(err.into()))?
+            }
+        };
+        Ok(EgoContext {
+            runtime_dir,
+            target_user: user.name,
+            target_uid: user.uid.as_raw(),
+            target_user_shell: user.shell,
+            target_user_homedir: user.dir,
+            dry_run: self.dry_run,
+            relay: self.relay,
+            a11y: self.a11y,
+            ibus: self.ibus,
+            machine: self.machine,
+        })
+    }
+}
+
+/// Result of a grant operation: environment for the target user, and the resources that the
+/// target user was given access to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Grant {
+    /// Environment variables for the target user's command, as `KEY=value`
+    pub env: Vec<String>,
+    /// Resources made accessible, e.g. `acl:user:ego:rwx:/run/user/1000/wayland-0`
+    pub resources: Vec<String>,
+    /// Shell commands to run as the target user before the command, see [`wrap_command`]
+    pub setup: Vec<String>,
+    /// Sockets waiting to be relayed with `--relay`, see [`start_relay`]
+    pub(crate) relayed: Vec<RelaySocket>,
+}
+
+impl Grant {
+    pub(crate) fn extend(&mut self, other: Grant) {
+        self.env.extend(other.env);
+        self.resources.extend(other.resources);
+        self.setup.extend(other.setup);
+        self.relayed.extend(other.relayed);
+    }
+
+    /// Add ACL entry for target user, or just print it in `--dry-run` mode.
+    pub(crate) fn add_acl(
+        &mut self,
+        ctx: &EgoContext,
+        path: &Path,
+        flags: u32,
+    ) -> Result<(), AnyErr> {
+        let entry = format!("user:{}:{}", ctx.target_user, acl_perm_str(flags));
+        if ctx.dry_run {
+            print_dry_run("acl", format_args!("{entry} {}", path.display()));
+        } else {
+            add_file_acl(path, ctx.target_uid, flags)?;
+        }
+        self.resources
+            .push(format!("acl:{entry}:{}", path.display()));
+        Ok(())
+    }
+}
+
+/// Entry point of the `ego` command line tool. `cmdline` includes the program name.
+///
+/// # Errors
+/// Any error that prevented launching the command.
+pub fn run(cmdline: &[String]) -> Result<(), AnyErr> {
+    let mut ego_args: Vec<String> = cmdline.iter().skip(1).cloned().collect();
+    let mut args = parse_args(cmdline);
+    logging::init_with_level(args.log_level, args.message_format);
+
+    if let Some(Action::Again { num }) = args.action {
+        let entry = history::history_entry(num)?;
+        if let Some(cwd) = &entry.cwd {
+            env::set_current_dir(cwd)
+                .map_err(|err| format!("Working directory '{}': {err}", cwd.display()))?;
+        }
+        ego_args = entry.args;
+        info!("Re-running: ego {}", shell_words::join(&ego_args));
+        args = parse_args(iter::once("ego".to_string()).chain(ego_args.iter().cloned()));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    warn!("Ego is built for Linux and not expected to work on '{}'", env::consts::OS);
+
+    match &args.action {
+        Some(Action::InstallLauncher { entry }) => {
+            get_target_user(&args.user)?;
+            return desktop::install_launcher(entry, &args.user, args.method);
+        }
+        Some(Action::UninstallLauncher { entry }) => return desktop::uninstall_launcher(entry),
+        Some(Action::Setup { user, group, dry_run }) => {
+            return setup::run_setup(user, group, *dry_run);
+        }
+        Some(Action::Doctor { json }) => return doctor::run_doctor(&args.user, *json),
+        Some(Action::History) => return history::list_history(),
+        Some(Action::Askpass { prompt }) => return dialog::askpass(prompt.as_deref()),
+        Some(Action::Relay { parent, dir, sockets }) => {
+            return relay::run_relay(*parent, dir, sockets);
+        }
+        Some(Action::Revoke { parent, uid, acls }) => {
+            return share::run_revoke(*parent, *uid, acls);
+        }
+        Some(Action::NotifyBridge { parent, prefix, socket }) => {
+            return notify::run_notify_bridge(*parent, socket, prefix.as_deref());
+        }
+        Some(Action::NotifyProxy { socket }) => return notify::run_notify_proxy(socket),
+        Some(Action::OpenListener { parent, user, socket }) => {
+            return handoff::run_open_listener(*parent, user, socket);
+        }
+        Some(Action::DeviceAcl { user, revoke, paths }) => {
+            return device::run_device_acl(user, *revoke, paths);
+        }
+        Some(Action::OpenUrl { args }) => return handoff::open_url(args),
+        Some(Action::Prepare { .. } | Action::Again { .. }) | None => {}
+    }
+    if args.list_shares {
+        return share::list_shares();
+    }
+    if !args.unshare.is_empty() {
+        return unshare(&args.unshare, args.dry_run);
+    }
+    check_prepare_args(&args)?;
+
+    let ctx = EgoContext::builder(&args.user)
+        .dry_run(args.dry_run)
+        .relay(args.relay)
+        .a11y(args.a11y)
+        .ibus(args.ibus)
+        .machine(args.machine.as_deref())
+        .build()?;
+
+    info!("Setting up Alter Ego for target user {} ({})", ctx.target_user, ctx.target_uid);
+
+    check_user_homedir(&ctx);
+
+    let method = args.method.unwrap_or_else(detect_method);
+    let mut grant = grant_requested(&ctx, &mut args, method)?;
+    if let Some(name) = &args.desktop_file {
+        args.command = desktop::desktop_command(name, &args.command)?;
+    }
+    if let Some(app_id) = &args.flatpak {
+        args.command = flatpak::flatpak_command(&ctx, app_id, &args.command)?;
+    }
+
+    grant.extend(prepare_session(&ctx, args.old_xhost)?);
+    start_relay(&ctx, &mut grant)?;
+    if ctx.dry_run {
+        for var in &grant.env {
+            print_dry_run("env", var);
+        }
+        for script in &grant.setup {
+            print_dry_run("setup", script);
+        }
+    }
+
+    if let Some(Action::Prepare { print_env, format }) = args.action {
+        if !ctx.dry_run {
+            logging::audit_launch(&ctx, "prepare", &[], &grant);
+        }
+        if print_env {
+            print!("{}", format_env(&grant.env, format));
+        }
+        return Ok(());
+    }
+
+    if !ctx.dry_run {
+        logging::audit_launch(&ctx, method.as_str(), &args.command, &grant);
+        history::record_launch(&ctx.target_user, method.as_str(), &ego_args);
+    }
+
+    let mut command = wrap_command(&ctx, &grant.setup, args.command)?;
+    if args.flatpak.is_some() {
+        command = flatpak::wrap_session_bus(&ctx, method, &grant.env, command)?;
+    }
+    launch(&ctx, method, grant.env, command)?;
+    Ok(())
+}
+
+/// Revoke shares and devices given to `--unshare`.
+fn unshare(specs: &[PathBuf], dry_run: bool) -> Result<(), AnyErr> {
+    for spec in specs {
+        for path in device::unshare_paths(spec)? {
+            share::unshare_path(&path, dry_run)?;
+        }
+    }
+    Ok(())
+}
+
+/// Grant access to resources requested on the command line: shares, devices, agents and files in
+/// command arguments. `method` is the method the command will be launched with.
+fn grant_requested(ctx: &EgoContext, args: &mut Args, method: Method) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    for spec in &args.share {
+        grant.extend(prepare_share(ctx, spec)?);
+    }
+    grant.extend(prepare_devices(ctx, &args.device)?);
+    if args.ssh_agent {
+        grant.extend(prepare_ssh_agent(ctx)?);
+    }
+    if args.gpg_agent {
+        grant.extend(prepare_gpg_agent(ctx)?);
+    }
+    if let Some(mode) = args.notifications {
+        let ret = notify::prepare_notifications(ctx, method, mode == NotifyMode::Prefix);
+        grant.extend(ret.map_err(EgoError::Integration)?);
+    }
+    if args.open_links {
+        grant.extend(handoff::prepare_open_links(ctx).map_err(EgoError::Integration)?);
+    }
+    if let Some(mode) = args.grant_files {
+        // With --desktop-file or --flatpak, all arguments are files. Otherwise skip the program name.
+        let program = args.desktop_file.is_none() && args.flatpak.is_none();
+        let skip = usize::from(program).min(args.command.len());
+        let ret = share::grant_args(ctx, &mut args.command[skip..], mode);
+        grant.extend(ret.map_err(EgoError::Share)?);
+    }
+    Ok(grant)
+}
+
+/// Grant target user access to a file or directory tree, recursively. The share is recorded, so
+/// it persists until revoked with `ego --unshare PATH`.
+///
+/// # Errors
+/// If the path does not exist or the ACLs cannot be changed.
+pub fn prepare_share(ctx: &EgoContext, spec: &ShareSpec) -> Result<Grant, EgoError> {
+    share::share_path(ctx, spec).map_err(EgoError::Share)
+}
+
+/// Grant target user read-write access to device nodes. Each entry is a device class (`camera`,
+/// `gamepad`, `audio`) or a `/dev` path of such a device. Like shares, the grants are recorded.
+/// Unless running as root, the ACLs are changed by `ego device-acl` through `sudo`.
+///
+/// # Errors
+/// If a device is unknown or the ACLs cannot be changed, e.g. because sudo does not allow it.
+pub fn prepare_devices(ctx: &EgoContext, devices: &[String]) -> Result<Grant, EgoError> {
+    device::grant_devices(ctx, devices).map_err(EgoError::Device)
+}
+
+/// Grant access to the SSH agent socket in `SSH_AUTH_SOCK` and return `SSH_AUTH_SOCK` for the
+/// target user.
+///
+/// # Errors
+/// If no agent is running or the ACLs cannot be changed.
+pub fn prepare_ssh_agent(ctx: &EgoContext) -> Result<Grant, EgoError> {
+    agent::prepare_ssh_agent(ctx).map_err(EgoError::Agent)
+}
+
+/// Grant access to the restricted gpg-agent extra socket. The returned setup commands point the
+/// target user's `gpg` at it.
+///
+/// # Errors
+/// If `gpgconf` fails or the ACLs cannot be changed.
+pub fn prepare_gpg_agent(ctx: &EgoContext) -> Result<Grant, EgoError> {
+    agent::prepare_gpg_agent(ctx).map_err(EgoError::Agent)
+}
+
+/// `ego prepare` exits right away, so options that need a helper process running alongside the
+/// command cannot be used with it.
+fn check_prepare_args(args: &Args) -> Result<(), ErrorWithHint> {
+    if !matches!(args.action, Some(Action::Prepare { .. })) {
+        return Ok(());
+    }
+    let helpers = [
+        (args.relay, "--relay"),
+        (args.a11y, "--a11y"),
+        (args.ibus, "--ibus"),
+        (args.notifications.is_some(), "--notifications"),
+        (args.open_links, "--open-links"),
+    ];
+    if let Some((_, option)) = helpers.iter().find(|(enabled, _)| *enabled) {
+        return Err(ErrorWithHint::new(
+            format!("{option} cannot be used with 'ego prepare'"),
+            format!(
+                "{option} needs a helper process that exits with ego, launch the command with ego instead"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Shell script that runs `setup` commands, then the command given as arguments.
+fn setup_script(setup: &[String]) -> String {
+    format!("{}; exec \"$@\"", setup.join("; "))
+}
+
+/// Wrap `command` in `/bin/sh -c` to run `setup` shell commands first, as the target user. An empty
+/// command is replaced with the target user's shell.
+///
+/// # Errors
+/// If the user's shell path is not valid UTF-8.
+pub fn wrap_command(
+    ctx: &EgoContext,
+    setup: &[String],
+    mut command: Vec<String>,
+) -> Result<Vec<String>, AnyErr> {
+    if setup.is_empty() {
+        return Ok(command);
+    }
+    if command.is_empty() {
+        let shell = require_with!(
+            ctx.target_user_shell.to_str(),
+            "User '{}' shell has unexpected characters",
+            ctx.target_user
+        );
+        command.push(shell.to_string());
+    }
+    let wrapper = [
+        "/bin/sh".into(),
+        "-c".into(),
+        setup_script(setup),
+        "sh".into(),
+    ];
+    command.splice(0..0, wrapper);
+    Ok(command)
+}
+
+/// Grant target user access to the runtime dir, display, audio, accessibility bus and input method.
+/// Sockets that need relaying are only started by [`start_relay`].
+///
+/// # Errors
+/// If any of the individual grant operations fails, classified by subsystem.
+pub fn prepare_session(ctx: &EgoContext, old_xhost: bool) -> Result<Grant, EgoError> {
+    let mut grant = Grant::default();
+
+    if ctx.relay {
+        // Sockets are relayed from a private dir, the runtime dir is not touched
+        grant.relayed.extend(relay::display_sockets(ctx)?);
+    } else {
+        grant.extend(prepare_runtime_dir(ctx).map_err(EgoError::RuntimeDir)?);
+        grant.extend(prepare_wayland(ctx).map_err(EgoError::Wayland)?);
+        grant.extend(prepare_pulseaudio(ctx).map_err(EgoError::Audio)?);
+    }
+    grant.extend(prepare_x11(ctx, old_xhost).map_err(EgoError::X11)?);
+    if ctx.a11y {
+        grant.relayed.extend(accessibility::a11y_socket(ctx));
+    }
+    let wayland = get_wayland_socket(ctx)
+        .map_err(EgoError::Wayland)?
+        .is_some();
+    let (im_env, im_socket) =
+        input_method::prepare_input_method(ctx, wayland).map_err(EgoError::Integration)?;
+    grant.env.extend(im_env);
+    grant.relayed.extend(im_socket);
+    Ok(grant)
+}
+
+/// Start a single relay process for all sockets in `grant` that wait for relaying, like the display
+/// and agent sockets with `--relay`. Call once, after all other grants were added to `grant`.
+///
+/// # Errors
+/// If the relay cannot be started or its sockets cannot be shared, classified by subsystem.
+pub fn start_relay(ctx: &EgoContext, grant: &mut Grant) -> Result<(), EgoError> {
+    let relayed = mem::take(&mut grant.relayed);
+    if !relayed.is_empty() {
+        grant.extend(relay::start_relay(ctx, &relayed)?);
+    }
+    Ok(())
+}
+
+/// Format `KEY=value` environment vars for `ego prepare --print-env`.
+fn format_env(vars: &[String], format: EnvFormat) -> String {
+    let pairs = vars.iter().filter_map(|v| v.split_once('='));
+    match format {
+        EnvFormat::Sh => pairs.fold(String::new(), |mut out, (key, value)| {
+            writeln!(out, "export {key}={}", shell_words::quote(value)).unwrap();
+            out
+        }),
+        EnvFormat::Json => {
+            let items: Vec<String> = pairs
+                .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+                .collect();
+            format!("{{{}}}\n", items.join(","))
+        }
+        // systemd EnvironmentFile= syntax, see systemd.exec(5)
+        EnvFormat::Env => pairs.fold(String::new(), |mut out, (key, value)| {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(out, "{key}=\"{escaped}\"").unwrap();
+            out
+        }),
+    }
+}
+
+/// Optionally get an environment variable.
+/// Returns `Ok(None)` for missing env variable.
+fn getenv_optional(key: &str) -> Result<Option<String>, SimpleError> {
+    match env::var(key) {
+        Ok(val) => Ok(Some(val)),
+        Err(VarError::NotPresent) => Ok(None),
+        // We could use Path type for non-Unicode paths, but it's not worth it. Fix your s*#t!
+        Err(VarError::NotUnicode(_)) => bail!("Env variable {key} invalid"),
+    }
+}
+
+/// Require an environment variable.
+fn getenv_path(key: &str) -> Result<PathBuf, SimpleError> {
+    match getenv_optional(key)? {
+        Some(val) => Ok(PathBuf::from(val)),
+        None => bail!("Env variable {key} unset"),
+    }
+}
+
+/// Get details of *target* user; on error, formats a nice user-friendly message with instructions.
+fn get_target_user(username: &str) -> Result<User, AnyErr> {
+    if let Some(user) = User::from_name(username)? {
+        return Ok(user);
+    }
+
+    debug!("Username '{username}' not found");
+
+    let mut hint = "Specify different user with --user= or create a new user".to_string();
+
+    // Find a free UID for a helpful error message.
+    // UIDs >=1000 are visible on login screen, so better avoid them.
+    //
+    // https://refspecs.linuxfoundation.org/LSB_5.0.0/LSB-Core-generic/LSB-Core-generic/uidrange.html
+    // > The system User IDs from 100 to 499 should be reserved for dynamic allocation by system
+    // > administrators and post install scripts using useradd.
+    for uid in 150..=499 {
+        if User::from_uid(Uid::from_raw(uid))?.is_none() {
+            hint = format!(
+                "{hint} with the command:\n    sudo useradd '{username}' --uid {uid} --create-home"
+            );
+            break;
+        }
+        debug!("User UID {uid} already exists");
+    }
+
+    Err(ErrorWithHint::new(format!("Unknown user '{username}'"), hint).into())
+}
+
+fn add_file_acl(path: &Path, uid: u32, flags: u32) -> Result<(), AnyErr> {
+    let mut acl = PosixACL::read_acl(path)?;
+    acl.set(Qualifier::User(uid), flags);
+    acl.write_acl(path)?;
+    Ok(())
+}
+
+/// Whether changing an ACL failed because we do not own the file, e.g. device nodes owned by root.
+fn is_permission_denied(err: &AnyErr) -> bool {
+    if let Some(err) = err.downcast_ref::<ACLError>() {
+        return err.kind() == PermissionDenied;
+    }
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == PermissionDenied)
+}
+
+/// Format ACL permission flags like `getfacl`, e.g. `r-x`
+fn acl_perm_str(flags: u32) -> String {
+    [(ACL_READ, 'r'), (ACL_WRITE, 'w'), (ACL_EXECUTE, 'x')]
+        .iter()
+        .map(|&(flag, c)| if flags & flag == 0 { '-' } else { c })
+        .collect()
+}
+
+/// Parse ACL permissions formatted with [`acl_perm_str`]
+fn parse_acl_perms(perms: &str) -> Option<u32> {
+    let chars: Vec<char> = perms.chars().collect();
+    let [r, w, x] = chars[..] else {
+        return None;
+    };
+    [
+        (r, 'r', ACL_READ),
+        (w, 'w', ACL_WRITE),
+        (x, 'x', ACL_EXECUTE),
+    ]
+    .iter()
+    .try_fold(0, |flags, &(c, expected, flag)| match c {
+        '-' => Some(flags),
+        c if c == expected => Some(flags | flag),
+        _ => None,
+    })
+}
+
+/// Print planned change in `--dry-run` mode.
+fn print_dry_run(what: &str, detail: impl Display) {
+    println!("[dry-run] {what}: {detail}");
+}
+
+/// Exec command, or just print it in `--dry-run` mode.
+fn launch_command(ctx: &EgoContext, program: &str, args: &[String]) -> Result<(), AnyErr> {
+    launch_command_env(ctx, program, args, &[])
+}
+
+/// Like [`launch_command`], with additional environment variables for `program`.
+fn launch_command_env(
+    ctx: &EgoContext,
+    program: &str,
+    args: &[String],
+    env: &[(&str, &OsStr)],
+) -> Result<(), AnyErr> {
+    if ctx.dry_run {
+        print_dry_run("command", format_args!("{program} {}", shell_words::join(args)));
+        return Ok(());
+    }
+    match exec_command_env(program, args, env) {
+        Err(err) if !have_command(program) => Err(EgoError::BackendMissing(err.into()).into()),
+        ret => Ok(ret?),
+    }
+}
+
+/// Remove user entry from file ACL, if present.
+fn remove_file_acl(path: &Path, uid: u32) -> Result<(), AnyErr> {
+    let mut acl = PosixACL::read_acl(path)?;
+    if acl.remove(Qualifier::User(uid)).is_some() {
+        acl.write_acl(path)?;
+    }
+    Ok(())
+}
+
+/// Clear permission `flags` from user entry in file ACL. The entry is removed if no permissions
+/// remain, other permissions are kept.
+fn clear_file_acl(path: &Path, uid: u32, flags: u32) -> Result<(), AnyErr> {
+    let mut acl = PosixACL::read_acl(path)?;
+    let Some(perms) = acl.get(Qualifier::User(uid)) else {
+        return Ok(());
+    };
+    if perms & !flags == 0 {
+        acl.remove(Qualifier::User(uid));
+    } else {
+        acl.set(Qualifier::User(uid), perms & !flags);
+    }
+    acl.write_acl(path)?;
+    Ok(())
+}
+
+/// Detect if user home directory does not exist or has wrong ownership.
+/// Returns the log level and message of the problem found, if any.
+fn homedir_problem(ctx: &EgoContext) -> Option<(Level, String)> {
+    let home = &ctx.target_user_homedir;
+    match fs::metadata(home) {
+        Ok(meta) => {
+            if meta.uid() == ctx.target_uid {
+                return None;
+            }
+            Some((
+                Level::Warn,
+                format!(
+                    "User {} home directory {} has incorrect ownership (expected UID {}, found {})",
+                    ctx.target_user,
+                    home.display(),
+                    ctx.target_uid,
+                    meta.uid()
+                ),
+            ))
+        }
+        Err(err) => {
+            // Report PermissionDenied as `info` level, user home directory is probably in a parent
+            // directory we have no access to, avoid nagging.
+            let level = match err.kind() {
+                PermissionDenied => Level::Info,
+                _ => Level::Warn,
+            };
+
+            Some((
+                level,
+                format!(
+                    "User {} home directory {} is not accessible: {err}",
+                    ctx.target_user,
+                    home.display(),
+                ),
+            ))
+        }
+    }
+}
+
+/// Report warning if user home directory does not exist or has wrong ownership
+fn check_user_homedir(ctx: &EgoContext) {
+    if let Some((level, msg)) = homedir_problem(ctx) {
+        log!(level, "{msg}");
+    }
+}
+
+/// Add execute perm to runtime dir, e.g. `/run/user/1000`
+///
+/// # Errors
+/// If runtime dir does not exist or the ACL cannot be changed.
+pub fn prepare_runtime_dir(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let path = &ctx.runtime_dir;
+    if !path.is_dir() {
+        bail!("'{}' is not a directory", path.display());
+    }
+    grant.add_acl(ctx, path, ACL_EXECUTE)?;
+    debug!("Runtime data dir '{}' configured", path.display());
+    Ok(grant)
+}
+
+/// `WAYLAND_DISPLAY` may be absolute path or relative to `XDG_RUNTIME_DIR`
+/// See <https://manpages.debian.org/experimental/libwayland-doc/wl_display_connect.3.en.html>
+fn get_wayland_socket(ctx: &EgoContext) -> Result<Option<PathBuf>, AnyErr> {
+    match getenv_optional("WAYLAND_DISPLAY")? {
+        None => Ok(None),
+        Some(display) => Ok(Some(ctx.runtime_dir.join(display))),
+    }
+}
+
+/// Add rwx permissions to Wayland socket (e.g. `/run/user/1000/wayland-0`)
+/// Return environment vars for `WAYLAND_DISPLAY`.
+///
+/// # Errors
+/// If `WAYLAND_DISPLAY` is invalid or the ACL cannot be changed.
+pub fn prepare_wayland(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let Some(path) = get_wayland_socket(ctx)? else {
+        debug!("Wayland: WAYLAND_DISPLAY not set, skipping");
+        return Ok(grant);
+    };
+
+    grant.add_acl(ctx, path.as_path(), ACL_RWX)?;
+
+    grant
+        .env
+        .push(format!("WAYLAND_DISPLAY={}", path.display()));
+    debug!("Wayland socket '{}' configured", path.display());
+    Ok(grant)
+}
+
+/// Detect `DISPLAY` and grant permissions via X11 protocol `ChangeHosts` command
+/// (or run `xhost` command if `--old-xhost` was used).
+/// Return environment vars for `DISPLAY`
+///
+/// # Errors
+/// If X11 access control could not be changed.
+pub fn prepare_x11(ctx: &EgoContext, old_xhost: bool) -> Result<Grant, AnyErr> {
+    let Some(display) = getenv_optional("DISPLAY")? else {
+        debug!("X11: DISPLAY not set, skipping");
+        return Ok(Grant::default());
+    };
+
+    if ctx.dry_run {
+        print_dry_run("x11", format_args!("add host SI:localuser:{}", ctx.target_user));
+    } else if old_xhost {
+        warn!(
+            "--old-xhost is deprecated. \
+            If there are issues with the new method, please report a bug."
+        );
+        x11_xhost_add_acl("localuser", &ctx.target_user)?;
+    } else {
+        x11_add_acl_with_fallback("localuser", &ctx.target_user)?;
+    }
+    // TODO should also test /tmp/.X11-unix/X0 permissions?
+
+    Ok(Grant {
+        env: vec![format!("DISPLAY={display}")],
+        resources: vec![format!("x11:SI:localuser:{}", ctx.target_user)],
+        ..Grant::default()
+    })
+}
+
+/// Add execute permissions to PulseAudio directory (e.g. `/run/user/1000/pulse`)
+/// Return environment vars for `PULSE_SERVER`.
+///
+/// The actual socket `/run/user/1000/pulse/native` already has full read-write permissions.
+///
+/// # Errors
+/// If socket has unexpected permissions, the cookie cannot be found or ACLs cannot be changed.
+pub fn prepare_pulseaudio(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let pulse_dir = ctx.runtime_dir.join("pulse");
+    let socket_path = pulse_dir.join("native");
+    let socket_meta = match socket_path.metadata() {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == NotFound => {
+            debug!("PulseAudio socket not found, skipping");
+            return Ok(grant);
+        }
+        Err(err) => bail!("'{}': {err}", socket_path.display()),
+    };
+
+    grant.add_acl(ctx, pulse_dir.as_path(), ACL_EXECUTE)?;
+
+    grant
+        .env
+        .extend(prepare_pulseaudio_socket(socket_path.as_path(), &socket_meta)?);
+    grant.extend(prepare_pulseaudio_cookie(ctx)?);
+
+    debug!("PulseAudio dir '{}' configured", pulse_dir.display());
+    Ok(grant)
+}
+
+/// Ensure permissions of PulseAudio socket `/run/user/1000/pulse/native`
+fn prepare_pulseaudio_socket(path: &Path, meta: &Metadata) -> Result<Vec<String>, AnyErr> {
+    let mode = meta.permissions().mode();
+
+    #[allow(clippy::items_after_statements)]
+    const WORLD_READ_PERMS: u32 = 0o006;
+    if mode & WORLD_READ_PERMS != WORLD_READ_PERMS {
+        bail!("Unexpected permissions on '{}': {:o}", path.display(), mode & 0o777);
+    }
+
+    let env = format!("PULSE_SERVER=unix:{}", path.to_str().unwrap());
+    Ok(vec![env])
+}
+
+/// Try various ways to discover the current user's PulseAudio authentication cookie.
+pub(crate) fn find_pulseaudio_cookie() -> Result<PathBuf, AnyErr> {
+    // Try PULSE_COOKIE
+    if let Some(path) = getenv_optional("PULSE_COOKIE")? {
+        return Ok(PathBuf::from(path));
+    }
+    // Try ~/.config/pulse/cookie
+    let home = getenv_path("HOME")?;
+    let path = home.join(".config/pulse/cookie");
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    // Try ~/.pulse-cookie, for older PulseAudio versions
+    let path = home.join(".pulse-cookie");
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    bail!(
+        "Cannot locate PulseAudio cookie \
+        (tried $PULSE_COOKIE, ~/.config/pulse/cookie, ~/.pulse-cookie)"
+    )
+}
+
+/// Publish current user's pulse-cookie for target user
+fn prepare_pulseaudio_cookie(ctx: &EgoContext) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let cookie_path = find_pulseaudio_cookie()?;
+    let target_path = ensure_ego_rundir(ctx, &mut grant)?.join("pulse-cookie");
+
+    debug!("Publishing PulseAudio cookie {} to {}", cookie_path.display(), target_path.display());
+    if ctx.dry_run {
+        let detail = format!("copy {} to {}", cookie_path.display(), target_path.display());
+        print_dry_run("file", detail);
+    } else {
+        fs::copy(cookie_path.as_path(), target_path.as_path())?;
+    }
+    grant.add_acl(ctx, target_path.as_path(), ACL_READ)?;
+
+    grant
+        .env
+        .push(format!("PULSE_COOKIE={}", target_path.to_str().unwrap()));
+    Ok(grant)
+}
+
+/// Create runtime dir for Ego itself (e.g. `/run/user/1000/ego`) and make it readable for target
+/// user. This directory us used to share state (e.g. PulseAudio auth cookie).
+fn ensure_ego_rundir(ctx: &EgoContext, grant: &mut Grant) -> Result<PathBuf, AnyErr> {
+    // XXX We assume that prepare_runtime_dir() has already been called.
+    let path = ctx.runtime_dir.join("ego");
+    if !path.is_dir() {
+        if ctx.dry_run {
+            print_dry_run("dir", format_args!("create {} (mode 700)", path.display()));
+        } else {
+            DirBuilder::new().mode(0o700).create(path.as_path())?;
+        }
+    }
+    // Set ACL either way, because target user may be different in every run.
+    grant.add_acl(ctx, path.as_path(), ACL_EXECUTE)?;
+    Ok(path)
+}
+
+/// Get persistent state dir for Ego (e.g. `~/.local/state/ego`), creating it if necessary.
+fn ensure_ego_statedir() -> Result<PathBuf, AnyErr> {
+    let base = match getenv_optional("XDG_STATE_HOME")? {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => getenv_path("HOME")?.join(".local/state"),
+    };
+    let path = base.join("ego");
+    if !path.is_dir() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path.as_path())?;
+    }
+    Ok(path)
+}
+
+/// Detect which method should be used
+#[must_use]
+pub fn detect_method() -> Method {
+    if !sd_booted() {
+        return Method::Sudo;
+    }
+    if !have_command("machinectl") {
+        // If booted using systemd, issue a warning
+        warn!("machinectl (systemd-container) is not installed");
+        warn!("Falling back to 'sudo', some desktop integration features may not work");
+        return Method::Sudo;
+    }
+    Method::Machinectl
+}
+
+/// Run `command` as the target user with `method`. `env` is usually [`Grant::env`] returned by
+/// [`prepare_session`]. An empty command starts the target user's shell.
+///
+/// # Errors
+/// [`EgoError::BackendMissing`] if the backend command is not installed, otherwise
+/// [`EgoError::BackendFailed`].
+pub fn launch(
+    ctx: &EgoContext,
+    method: Method,
+    env: Vec<String>,
+    command: Vec<String>,
+) -> Result<(), EgoError> {
+    let ret = match method {
+        Method::Sudo => run_sudo_command(ctx, env, command),
+        Method::Machinectl => run_machinectl_command(ctx, &env, command, false),
+        Method::MachinectlBare => run_machinectl_command(ctx, &env, command, true),
+    };
+    ret.map_err(|err| match err.downcast::<EgoError>() {
+        Ok(err) => *err,
+        Err(err) => EgoError::BackendFailed(err),
+    })
+}
+
+/// Shell snippet that creates the private fallback runtime dir as the target user, then runs the
+/// command. Refuses to use a directory that is not owned by the target user.
+const RUNTIME_DIR_SCRIPT: &str = "d=\"$XDG_RUNTIME_DIR\"; mkdir -m 0700 \"$d\" 2>/dev/null; \
+    if [ -d \"$d\" ] && [ ! -L \"$d\" ] && [ -O \"$d\" ]; then chmod 0700 \"$d\"; \
+    else echo \"ego: warning: could not create runtime dir $d\" >&2; unset XDG_RUNTIME_DIR; fi";
+
+/// Runtime dir of the target user in sudo mode
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SudoRuntimeDir {
+    /// Created by logind, e.g. `/run/user/150`
+    Logind(PathBuf),
+    /// Private directory that the command must create as the target user
+    Private(PathBuf),
+}
+
+/// `sudo` does not create a logind session, so the target user often has no runtime dir. Use the
+/// logind runtime dir if it exists, otherwise a private directory in `/tmp`. Not `$TMPDIR`, which
+/// the invoking user controls.
+pub(crate) fn sudo_runtime_dir(ctx: &EgoContext) -> SudoRuntimeDir {
+    let logind_dir = PathBuf::from(format!("/run/user/{}", ctx.target_uid));
+    if logind_dir.is_dir() {
+        return SudoRuntimeDir::Logind(logind_dir);
+    }
+    SudoRuntimeDir::Private(PathBuf::from(format!("/tmp/ego-runtime-{}", ctx.target_uid)))
+}
+
+fn run_sudo_command(
+    ctx: &EgoContext,
+    mut envvars: Vec<String>,
+    remote_cmd: Vec<String>,
+) -> Result<(), AnyErr> {
+    if !remote_cmd.is_empty() && remote_cmd[0].starts_with('-') {
+        bail!("Command may not start with '-' (command is: '{}')", remote_cmd[0]);
+    }
+    if let Some(machine) = &ctx.machine {
+        bail!("Cannot launch in machine '{machine}' with sudo, use --machinectl");
+    }
+
+    let remote_cmd = match sudo_runtime_dir(ctx) {
+        SudoRuntimeDir::Logind(dir) => {
+            envvars.push(format!("XDG_RUNTIME_DIR={}", dir.display()));
+            remote_cmd
+        }
+        SudoRuntimeDir::Private(dir) => {
+            info!(
+                "User {} has no runtime dir, using private directory '{}'",
+                ctx.target_user,
+                dir.display()
+            );
+            if sd_booted() && have_command("loginctl") {
+                info!(
+                    "To give user {0} a runtime dir, run: sudo loginctl enable-linger {0} \
+                    (undo with: sudo loginctl disable-linger {0})",
+                    ctx.target_user
+                );
+            }
+            envvars.push(format!("XDG_RUNTIME_DIR={}", dir.display()));
+            wrap_command(ctx, &[RUNTIME_DIR_SCRIPT.to_string()], remote_cmd)?
+        }
+    };
+
+    let mut args = vec!["-Hiu".to_string(), ctx.target_user.clone()];
+    let mut askpass = None;
+    // If SUDO_ASKPASS envvar is set, add -A argument to use the askpass agent
+    if let Ok(Some(_)) = getenv_optional("SUDO_ASKPASS") {
+        debug!("SUDO_ASKPASS detected");
+        args.push("-A".into());
+    } else if dialog::no_terminal() && !ctx.dry_run {
+        // Without a terminal, sudo cannot ask for password: use `ego askpass`
+        let helper = dialog::install_askpass()?;
+        debug!("No terminal, using askpass helper '{}'", helper.display());
+        askpass = Some(helper);
+        args.push("-A".into());
+        warn!("No terminal, sudo will ask for password with a dialog");
+        warn!(
+            "To launch without a password, allow it with a polkit rule or sudoers NOPASSWD entry \
+            (see 'sudo ego setup')"
+        );
+    }
+    args.extend(envvars);
+    args.extend(remote_cmd);
+
+    info!("Running command: sudo {}", args.join(" "));
+    let env: Vec<_> = askpass
+        .iter()
+        .map(|p| ("SUDO_ASKPASS", p.as_os_str()))
+        .collect();
+    launch_command_env(ctx, "sudo", &args, &env)?;
+    Ok(())
+}
+
+#[allow(clippy::format_push_string)]
+fn machinectl_remote_command(remote_cmd: Vec<String>, envvars: &[String], bare: bool) -> String {
+    let mut cmd = String::new();
+
+    if !bare {
+        // Split env variables by '=', to pass just their names
+        let env_names = envvars
+            .iter()
+            .map(|v| v.split('=').next().expect("Unexpected data in envvars"));
+
+        // Set environment variables in systemd
+        cmd.push_str(&format!(
+            "dbus-update-activation-environment --systemd {}; ",
+            shell_words::join(env_names)
+        ));
+        // TODO: Should we support desktop-portals other than gtk?
+        // XXX what happens if the desktop-portal is already running but with an outdated environment?
+        cmd.push_str("systemctl --user start xdg-desktop-portal-gtk; ");
+    }
+    cmd.push_str(&format!("exec {}", shell_words::join(remote_cmd)));
+    cmd
+}
+
+fn run_machinectl_command(
+    ctx: &EgoContext,
+    envvars: &[String],
+    remote_cmd: Vec<String>,
+    bare: bool,
+) -> Result<(), AnyErr> {
+    let envvars = match &ctx.machine {
+        Some(machine) => &machine::prepare_machine(ctx, machine, envvars)?,
+        None => envvars,
+    };
+    let mut args = vec!["shell".to_string()];
+    args.push(format!("--uid={}", ctx.target_user));
+    args.extend(envvars.iter().map(|v| format!("-E{v}")));
+    args.push("--".to_string());
+    args.push(ctx.machine.as_deref().unwrap_or(".host").to_string());
+
+    // I wish this could be done without going through /bin/sh, but seems necessary.
+    args.push("/bin/sh".to_string());
+    args.push("-c".to_string());
+    let remote_cmd = if remote_cmd.is_empty() {
+        vec![
+            require_with!(
+                ctx.target_user_shell.to_str(),
+                "User '{}' shell has unexpected characters",
+                ctx.target_user
+            )
+            .to_string(),
+        ]
+    } else {
+        remote_cmd
+    };
+    args.push(machinectl_remote_command(remote_cmd, envvars, bare));
+
+    info!("Running command: machinectl {}", shell_words::join(&args));
+    launch_command(ctx, "machinectl", &args)?;
+    Ok(())
+}
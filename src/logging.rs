@@ -1,14 +1,49 @@
-//! Logging for command line output.
+//! Logging for command line output, and the audit log of launches.
 //! Adapted from `simple_logger` by Sam Clements: <https://github.com/borntyping/rust-simple_logger>
 
-use crate::util::paint;
+use crate::cli::MessageFormat;
+use crate::util::{json_string, paint};
+use crate::{EgoContext, Grant};
 use anstyle::{AnsiColor, Color, Style};
-use log::{Level, Log, Metadata, Record, trace};
+use log::{Level, Log, Metadata, Record, debug, trace};
+use nix::unistd::Uid;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 struct SimpleLogger {
     level: Level,
 }
 
+/// Set when `--message-format=json` is in effect
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Whether messages are printed as JSON lines (`--message-format=json`)
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Format a message as a JSON line, for `--message-format=json`.
+pub fn json_message(level: &str, subsystem: &str, message: &str, hint: Option<&str>) -> String {
+    format!(
+        "{{\"level\":{},\"subsystem\":{},\"message\":{},\"hint\":{}}}",
+        json_string(level),
+        json_string(subsystem),
+        json_string(message),
+        hint.map_or_else(|| "null".to_string(), json_string)
+    )
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
 const COLOR_TRACE: Style = Style::new().fg_color(Some(Color::Ansi(AnsiColor::Magenta)));
 const COLOR_WARN: Style = Style::new()
     .fg_color(Some(Color::Ansi(AnsiColor::Yellow)))
@@ -26,6 +61,14 @@ impl Log for SimpleLogger {
         if !self.enabled(record.metadata()) {
             return;
         }
+        if json_output() {
+            // Module path like `ego::x11`, the crate root is `ego`
+            let target = record.target();
+            let subsystem = target.strip_prefix("ego::").unwrap_or(target);
+            let message = record.args().to_string();
+            eprintln!("{}", json_message(level_name(record.level()), subsystem, &message, None));
+            return;
+        }
         match record.level() {
             Level::Trace => {
                 let target = if record.target().is_empty() {
@@ -52,10 +95,83 @@ impl Log for SimpleLogger {
 
 /// Initializes the global logger with a `SimpleLogger` instance with
 /// `max_log_level` set to a specific log level.
-pub fn init_with_level(level: Level) {
+pub fn init_with_level(level: Level, format: MessageFormat) {
+    JSON_OUTPUT.store(format == MessageFormat::Json, Ordering::Relaxed);
     let logger = SimpleLogger { level };
     log::set_boxed_logger(Box::new(logger)).expect("Set logger failed");
     log::set_max_level(level.to_level_filter());
 
     trace!("Log level {level}");
 }
+
+/// Stable `MESSAGE_ID` of audit log entries, filter with `journalctl MESSAGE_ID=...`
+pub const AUDIT_MESSAGE_ID: &str = "3b8d9c1f0a6e4e27b5c94d2f7e1a6c08";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Syslog facility `LOG_AUTHPRIV` with severity `LOG_INFO`
+const SYSLOG_PRIORITY: u8 = (10 << 3) | 6;
+
+/// Append a field in journald native protocol format.
+/// <https://systemd.io/JOURNAL_NATIVE_PROTOCOL/>
+pub fn journal_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Send a structured audit entry about a launch to the systemd journal, or syslog if journald is
+/// not running. Errors are only reported at debug level; auditing never prevents a launch.
+pub fn audit_launch(ctx: &EgoContext, method: &str, command: &[String], grant: &Grant) {
+    let (target_user, target_uid) = (ctx.target_user(), ctx.target_uid());
+    let invoking_uid = Uid::current();
+    let command = shell_words::join(command);
+    let grants = grant.resources.join(" ");
+    let env_names: Vec<&str> = grant
+        .env
+        .iter()
+        .filter_map(|v| v.split('=').next())
+        .collect();
+    let env_names = env_names.join(" ");
+    let message = format!(
+        "UID {invoking_uid} running '{command}' as user {target_user} ({target_uid}) via {method}"
+    );
+
+    let mut buf = Vec::new();
+    for (key, value) in [
+        ("MESSAGE", message.as_str()),
+        ("MESSAGE_ID", AUDIT_MESSAGE_ID),
+        ("PRIORITY", "6"),
+        ("SYSLOG_FACILITY", "10"),
+        ("SYSLOG_IDENTIFIER", "ego"),
+        ("EGO_INVOKING_UID", &invoking_uid.to_string()),
+        ("EGO_TARGET_USER", target_user),
+        ("EGO_TARGET_UID", &target_uid.to_string()),
+        ("EGO_METHOD", method),
+        ("EGO_COMMAND", &command),
+        ("EGO_GRANTS", &grants),
+        ("EGO_ENV_VARS", &env_names),
+    ] {
+        journal_field(&mut buf, key, value);
+    }
+
+    let result = UnixDatagram::unbound().and_then(|sock| {
+        if sock.send_to(&buf, JOURNAL_SOCKET).is_ok() {
+            return Ok(());
+        }
+        trace!("Journal not available, falling back to syslog");
+        let line = format!(
+            "<{SYSLOG_PRIORITY}>ego[{}]: {message}; granted: {grants}; env: {env_names}",
+            process::id()
+        );
+        sock.send_to(line.as_bytes(), SYSLOG_SOCKET).map(|_| ())
+    });
+    if let Err(err) = result {
+        debug!("Could not write audit log: {err}");
+    }
+}
@@ -0,0 +1,151 @@
+//! Launching into another machine (`--machine NAME`), e.g. a systemd-nspawn container, instead of
+//! the host. Sockets and files that the command needs are bind-mounted into the machine with
+//! `machinectl bind`, and environment variables are translated to the paths inside the machine.
+//!
+//! The target user must have the same UID in the machine as on the host, because access to the
+//! sockets is granted with ACLs on the host. Helpers that run ego as the target user
+//! (`--notifications`, `--open-links`) cannot be used, ego is not available in the machine.
+
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::util::run_command;
+use crate::{EgoContext, getenv_optional, print_dry_run};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+/// Directory inside the machine where sockets are bind-mounted
+const MACHINE_DIR: &str = "/run/ego";
+/// X11 sockets are bind-mounted to the same path, since `DISPLAY` cannot name a path
+const X11_DIR: &str = "/tmp/.X11-unix";
+
+/// Validate machine name as accepted by machined, e.g. `fedora-40`.
+pub fn validate_machine_name(name: &str) -> Result<(), AnyErr> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with(['.', '-'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        bail!("Invalid machine name '{name}'");
+    }
+    Ok(())
+}
+
+/// Check that machine `name` is running.
+fn check_running(name: &str) -> Result<(), AnyErr> {
+    let args = ["show", "--property=State", "--value", name].map(String::from);
+    let state = match run_command("machinectl", &args) {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        Err(err) => {
+            debug!("machinectl show: {err}");
+            String::new()
+        }
+    };
+    if state != "running" {
+        return Err(ErrorWithHint::new(
+            format!("Machine '{name}' is not running"),
+            format!("Start it with: machinectl start {name}"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Split environment variable value into prefix, host path and suffix, if it refers to a path:
+/// `/run/user/1000/wayland-0`, `unix:/run/...` or a D-Bus address `unix:path=/run/...,guid=...`.
+pub fn split_env_path(value: &str) -> Option<(&str, &str, &str)> {
+    let start = if value.starts_with('/') {
+        0
+    } else if let Some(rest) = value.strip_prefix("unix:path=") {
+        value.len() - rest.len()
+    } else if value
+        .strip_prefix("unix:")
+        .is_some_and(|rest| rest.starts_with('/'))
+    {
+        "unix:".len()
+    } else {
+        return None;
+    };
+    let end = value[start..]
+        .find(',')
+        .map_or(value.len(), |pos| start + pos);
+    Some((&value[..start], &value[start..end], &value[end..]))
+}
+
+/// Bind mount of a host path into the machine, for environment variable `key`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bind {
+    pub key: String,
+    pub host: PathBuf,
+    pub target: PathBuf,
+}
+
+/// Translate environment for the machine. Variables that refer to existing host paths are changed
+/// to `/run/ego/NAME`; returns the new environment and the paths to bind.
+pub fn translate_env(envvars: &[String]) -> (Vec<String>, Vec<Bind>) {
+    let mut env = Vec::new();
+    let mut binds = Vec::new();
+    for var in envvars {
+        let Some((key, value)) = var.split_once('=') else {
+            env.push(var.clone());
+            continue;
+        };
+        match split_env_path(value) {
+            Some((prefix, path, suffix)) if Path::new(path).exists() => {
+                let target = Path::new(MACHINE_DIR).join(key);
+                env.push(format!("{key}={prefix}{}{suffix}", target.display()));
+                binds.push(Bind { key: key.to_string(), host: PathBuf::from(path), target });
+            }
+            _ => env.push(var.clone()),
+        }
+    }
+    (env, binds)
+}
+
+fn machine_bind(ctx: &EgoContext, machine: &str, bind: &Bind) -> Result<(), AnyErr> {
+    let (host, target) = (&bind.host, &bind.target);
+    let args = vec![
+        "bind".to_string(),
+        "--mkdir".into(),
+        machine.into(),
+        host.display().to_string(),
+        target.display().to_string(),
+    ];
+    if ctx.dry_run {
+        print_dry_run("bind", format_args!("machinectl {}", shell_words::join(&args)));
+        return Ok(());
+    }
+    run_command("machinectl", &args)?;
+    debug!("Bound '{}' to '{}' in machine {machine}", host.display(), target.display());
+    Ok(())
+}
+
+/// Bind-mount sockets into `machine` and return environment with paths inside the machine. If a
+/// socket cannot be bound, the command is started without the environment variable that refers
+/// to it.
+pub fn prepare_machine(
+    ctx: &EgoContext,
+    machine: &str,
+    envvars: &[String],
+) -> Result<Vec<String>, AnyErr> {
+    validate_machine_name(machine)?;
+    if !ctx.dry_run {
+        check_running(machine)?;
+    }
+    let (mut env, mut binds) = translate_env(envvars);
+    if getenv_optional("DISPLAY")?.is_some() && Path::new(X11_DIR).is_dir() {
+        let (host, target) = (PathBuf::from(X11_DIR), PathBuf::from(X11_DIR));
+        binds.push(Bind { key: "DISPLAY".into(), host, target });
+    }
+    for bind in &binds {
+        if let Err(err) = machine_bind(ctx, machine, bind) {
+            warn!(
+                "Could not bind '{}' into machine {machine}, continuing without {}: {err}",
+                bind.host.display(),
+                bind.key
+            );
+            env.retain(|var| var.split_once('=').is_none_or(|(key, _)| key != bind.key));
+        }
+    }
+    Ok(env)
+}
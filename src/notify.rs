@@ -0,0 +1,374 @@
+//! Notification bridge (`--notifications`): show desktop notifications of target user's
+//! applications through the invoking user's notification daemon.
+//!
+//! Two helper processes are involved:
+//! * `ego notify-bridge` runs as the invoking user and listens on a socket in the relay dir. It
+//!   accepts peer-to-peer D-Bus connections and forwards only `org.freedesktop.Notifications`
+//!   methods to the invoking user's session bus.
+//! * `ego notify-proxy` runs as the target user, owns `org.freedesktop.Notifications` on the
+//!   target user's session bus, and forwards to the bridge.
+//!
+//! `NotificationClosed` and `ActionInvoked` signals are forwarded back, but only for notifications
+//! created through the bridge.
+
+use crate::cli::Method;
+use crate::errors::{AnyErr, EgoError, ErrorWithHint};
+use crate::relay::{create_relay_dir, spawn_helper, wait_for_parent};
+use crate::util::system_ego;
+use crate::{EgoContext, Grant, SudoRuntimeDir, print_dry_run, sudo_runtime_dir};
+use log::debug;
+use posix_acl::{ACL_READ, ACL_WRITE};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{process, thread};
+use zbus::blocking::{self, MessageIterator};
+use zbus::export::serde::Serialize;
+use zbus::message::Type;
+use zbus::zvariant::{DynamicType, OwnedValue};
+use zbus::{Guid, MatchRule, Message, fdo, interface};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+/// Name of the bridge socket in the relay dir
+const BRIDGE_SOCKET: &str = "notifications";
+
+/// Hints that only affect presentation. Others, like `image-path` and `sound-file`, make the
+/// notification daemon open files as the invoking user.
+const SAFE_HINTS: [&str; 12] = [
+    "urgency",
+    "category",
+    "desktop-entry",
+    "transient",
+    "resident",
+    "action-icons",
+    "suppress-sound",
+    "x",
+    "y",
+    "image-data",
+    "image_data",
+    "icon_data",
+];
+
+/// Drop hints that are not in [`SAFE_HINTS`].
+pub fn filter_hints<V>(hints: HashMap<String, V>) -> HashMap<String, V> {
+    hints
+        .into_iter()
+        .filter(|(key, _)| {
+            let safe = SAFE_HINTS.contains(&key.as_str());
+            if !safe {
+                debug!("Notification: dropping hint '{key}'");
+            }
+            safe
+        })
+        .collect()
+}
+
+/// Only icon names are allowed as application icon, not file paths or `file://` URIs.
+pub fn filter_icon(icon: String) -> String {
+    if icon.contains('/') { String::new() } else { icon }
+}
+
+/// Remove `<img>` tags from body markup, images are loaded from files like `image-path`.
+pub fn strip_images(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(start) = rest.to_ascii_lowercase().find("<img") {
+        out.push_str(&rest[..start]);
+        rest = rest[start..].split_once('>').map_or("", |(_, after)| after);
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Prefix notification summary with target user's name, e.g. `[ego] New message`.
+pub fn prefix_summary(prefix: Option<&str>, summary: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("[{prefix}] {summary}"),
+        None => summary.to_string(),
+    }
+}
+
+/// `org.freedesktop.Notifications` implementation that forwards method calls to `conn`: the
+/// invoking user's session bus in the bridge, the bridge connection in the proxy.
+struct Forwarder {
+    conn: zbus::Connection,
+    destination: Option<&'static str>,
+    prefix: Option<String>,
+    /// Notifications created through the bridge, others cannot be closed
+    ids: Option<Arc<Mutex<HashSet<u32>>>>,
+}
+
+impl Forwarder {
+    async fn call<B>(&self, method: &str, body: &B) -> zbus::Result<Message>
+    where
+        B: Serialize + DynamicType,
+    {
+        let destination = self.destination;
+        let path = NOTIFICATIONS_PATH;
+        let interface = Some(NOTIFICATIONS_NAME);
+        self.conn
+            .call_method(destination, path, interface, method, body)
+            .await
+    }
+}
+
+#[interface(name = "org.freedesktop.Notifications")]
+impl Forwarder {
+    #[allow(clippy::too_many_arguments)]
+    async fn notify(
+        &self,
+        app_name: String,
+        replaces_id: u32,
+        app_icon: String,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+    ) -> fdo::Result<u32> {
+        if let Some(ids) = &self.ids {
+            if replaces_id != 0 && !ids.lock().unwrap().contains(&replaces_id) {
+                return Err(fdo::Error::AccessDenied("Unknown notification".into()));
+            }
+        }
+        let summary = prefix_summary(self.prefix.as_deref(), &summary);
+        let (app_icon, body, hints) =
+            (filter_icon(app_icon), strip_images(&body), filter_hints(hints));
+        let args = (app_name, replaces_id, app_icon, summary, body, actions, hints, expire_timeout);
+        let id: u32 = self.call("Notify", &args).await?.body().deserialize()?;
+        if let Some(ids) = &self.ids {
+            ids.lock().unwrap().insert(id);
+        }
+        Ok(id)
+    }
+
+    async fn close_notification(&self, id: u32) -> fdo::Result<()> {
+        if let Some(ids) = &self.ids {
+            if !ids.lock().unwrap().contains(&id) {
+                return Err(fdo::Error::AccessDenied("Unknown notification".into()));
+            }
+        }
+        self.call("CloseNotification", &(id,)).await?;
+        Ok(())
+    }
+
+    async fn get_capabilities(&self) -> fdo::Result<Vec<String>> {
+        Ok(self
+            .call("GetCapabilities", &())
+            .await?
+            .body()
+            .deserialize()?)
+    }
+
+    #[zbus(out_args("name", "vendor", "version", "spec_version"))]
+    async fn get_server_information(&self) -> fdo::Result<(String, String, String, String)> {
+        Ok(self
+            .call("GetServerInformation", &())
+            .await?
+            .body()
+            .deserialize()?)
+    }
+}
+
+/// Re-emit notification signal `msg` on `conn` if `accept` its notification id.
+fn reemit(msg: &Message, conn: &blocking::Connection, accept: impl Fn(u32) -> bool) -> bool {
+    let header = msg.header();
+    if header.message_type() != Type::Signal {
+        return true;
+    }
+    let Some(member) = header.member() else {
+        return true;
+    };
+    let body = msg.body();
+    let ret = match member.as_str() {
+        "NotificationClosed" => match body.deserialize::<(u32, u32)>() {
+            Ok(args) if accept(args.0) => {
+                conn.emit_signal(None::<()>, NOTIFICATIONS_PATH, NOTIFICATIONS_NAME, member, &args)
+            }
+            _ => return true,
+        },
+        "ActionInvoked" | "ActivationToken" => match body.deserialize::<(u32, String)>() {
+            Ok(args) if accept(args.0) => {
+                conn.emit_signal(None::<()>, NOTIFICATIONS_PATH, NOTIFICATIONS_NAME, member, &args)
+            }
+            _ => return true,
+        },
+        _ => return true,
+    };
+    if let Err(err) = &ret {
+        debug!("Forwarding {member} signal failed: {err}");
+    }
+    ret.is_ok()
+}
+
+/// Serve one proxy connection, forward signals until the proxy disconnects.
+fn serve_bridge_client(
+    stream: UnixStream,
+    session: &blocking::Connection,
+    prefix: Option<String>,
+) -> Result<(), AnyErr> {
+    let ids = Arc::new(Mutex::new(HashSet::new()));
+    let forwarder = Forwarder {
+        conn: session.inner().clone(),
+        destination: Some(NOTIFICATIONS_NAME),
+        prefix,
+        ids: Some(ids.clone()),
+    };
+    let client = blocking::connection::Builder::unix_stream(stream)
+        .server(Guid::generate())?
+        .p2p()
+        .serve_at(NOTIFICATIONS_PATH, forwarder)?
+        .build()?;
+
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(NOTIFICATIONS_NAME)?
+        .interface(NOTIFICATIONS_NAME)?
+        .build();
+    for msg in MessageIterator::for_match_rule(rule, session, None)? {
+        if !reemit(&msg?, &client, |id| ids.lock().unwrap().contains(&id)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// `ego notify-bridge`: accept proxy connections on `socket` until process `parent` exits.
+pub fn run_notify_bridge(parent: u32, socket: &Path, prefix: Option<&str>) -> Result<(), AnyErr> {
+    let session = blocking::Connection::session()?;
+    let listener = UnixListener::bind(socket)?;
+    let prefix = prefix.map(String::from);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let (session, prefix) = (session.clone(), prefix.clone());
+            thread::spawn(move || {
+                if let Err(err) = serve_bridge_client(stream, &session, prefix) {
+                    debug!("Notification bridge: {err}");
+                }
+            });
+        }
+    });
+
+    wait_for_parent(parent);
+    debug!("Parent exited, stopping notification bridge");
+    fs::remove_file(socket)?;
+    // Removed by the relay instead, if it also uses the dir
+    if let Some(dir) = socket.parent() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// `ego notify-proxy`: own the notifications name on target user's session bus and forward to the
+/// bridge at `socket`, until the bridge goes away.
+pub fn run_notify_proxy(socket: &Path) -> Result<(), AnyErr> {
+    let stream = UnixStream::connect(socket)?;
+    let bridge = blocking::connection::Builder::unix_stream(stream)
+        .p2p()
+        .build()?;
+    let forwarder =
+        Forwarder { conn: bridge.inner().clone(), destination: None, prefix: None, ids: None };
+    let session = blocking::connection::Builder::session()
+        .and_then(|builder| builder.serve_at(NOTIFICATIONS_PATH, forwarder))
+        .and_then(|builder| builder.name(NOTIFICATIONS_NAME))
+        .and_then(blocking::connection::Builder::build)
+        .map_err(|err| EgoError::Integration(proxy_error(&err).into()))?;
+
+    for msg in MessageIterator::from(&bridge) {
+        let Ok(msg) = msg else { break };
+        reemit(&msg, &session, |_| true);
+    }
+    Ok(())
+}
+
+/// Explain why `ego notify-proxy` could not register on target user's session bus.
+fn proxy_error(err: &zbus::Error) -> ErrorWithHint {
+    if matches!(err, zbus::Error::NameTaken) {
+        return ErrorWithHint::new(
+            format!("Notifications not forwarded: {NOTIFICATIONS_NAME} is already owned"),
+            "The target user runs its own notification daemon, stop it or launch without \
+            --notifications"
+                .into(),
+        );
+    }
+    ErrorWithHint::new(
+        format!("Notifications not forwarded: cannot connect to session bus: {err}"),
+        "Launch with --machinectl, which starts a session bus for the target user".into(),
+    )
+}
+
+/// Shell command for the setup script that starts `ego notify-proxy` in the background. Errors of
+/// the proxy go to the command's stderr.
+pub fn proxy_setup_command(exe: &str, socket: &Path) -> String {
+    let socket = socket.display().to_string();
+    let args = shell_words::join([exe, "notify-proxy", socket.as_str()]);
+    format!("({args} </dev/null >/dev/null &)")
+}
+
+/// With `sudo`, the target user has no session bus unless it has a logind session. Only root can
+/// look into an existing runtime dir, otherwise the bus is assumed to be there.
+pub fn check_session_bus(ctx: &EgoContext, method: Method) -> Result<(), ErrorWithHint> {
+    if method != Method::Sudo {
+        return Ok(());
+    }
+    let missing = match sudo_runtime_dir(ctx) {
+        SudoRuntimeDir::Private(_) => true,
+        SudoRuntimeDir::Logind(dir) => fs::symlink_metadata(dir.join("bus"))
+            .is_err_and(|err| err.kind() == ErrorKind::NotFound),
+    };
+    if missing {
+        return Err(ErrorWithHint::new(
+            format!(
+                "User {} has no session bus, notifications cannot be forwarded",
+                ctx.target_user
+            ),
+            format!(
+                "Launch with --machinectl, or give the user a session bus with: \
+                sudo loginctl enable-linger {}",
+                ctx.target_user
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Start the notification bridge and have the target user's setup script start the proxy.
+pub fn prepare_notifications(
+    ctx: &EgoContext,
+    method: Method,
+    prefix: bool,
+) -> Result<Grant, AnyErr> {
+    check_session_bus(ctx, method)?;
+    let mut grant = Grant::default();
+    let dir = create_relay_dir(ctx, &mut grant)?;
+    let socket = dir.join(BRIDGE_SOCKET);
+    let socket_arg =
+        require_with!(socket.to_str(), "Path '{}' is not valid UTF-8", socket.display());
+
+    let mut args = vec![
+        "notify-bridge".to_string(),
+        "--parent".into(),
+        process::id().to_string(),
+    ];
+    if prefix {
+        args.extend(["--prefix".into(), ctx.target_user.clone()]);
+    }
+    args.push(socket_arg.to_string());
+    if ctx.dry_run {
+        print_dry_run("notifications", format!("ego {}", shell_words::join(&args)));
+    } else {
+        spawn_helper(&args, std::slice::from_ref(&socket))?;
+    }
+    grant.add_acl(ctx, &socket, ACL_READ | ACL_WRITE)?;
+
+    let exe = system_ego()?;
+    let exe = require_with!(exe.to_str(), "Ego path '{}' is not valid UTF-8", exe.display());
+    grant.setup.push(proxy_setup_command(exe, &socket));
+    debug!("Notification bridge '{}' configured", socket.display());
+    Ok(grant)
+}
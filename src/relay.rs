@@ -0,0 +1,365 @@
+//! Socket relay mode (`--relay`): instead of adding an ACL to our whole runtime dir, a small relay
+//! process listens on sockets in a private directory that the target user can access, and
+//! forwards connections to the real Wayland, PulseAudio and `PipeWire` sockets.
+//!
+//! File descriptors passed over the sockets (`SCM_RIGHTS`) are forwarded as well, which Wayland
+//! and `PipeWire` rely on.
+//!
+//! The relay is also used for the accessibility bus, which cannot be shared with ACLs.
+
+use crate::errors::{AnyErr, EgoError};
+use crate::{EgoContext, Grant, find_pulseaudio_cookie, get_wayland_socket, print_dry_run};
+use log::debug;
+use nix::cmsg_space;
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+use nix::unistd::Uid;
+use posix_acl::{ACL_EXECUTE, ACL_READ, ACL_WRITE};
+use std::fmt::Write as _;
+use std::fs::{self, DirBuilder};
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, parent_id};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use std::{env, process, thread};
+
+/// Wayland allows up to 28 file descriptors per message
+const MAX_FDS: usize = 32;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A socket to relay: `name` in the relay dir forwards to `target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelaySocket {
+    pub name: &'static str,
+    pub target: PathBuf,
+    /// Environment variable for the target user, the listening path is appended
+    pub env_prefix: &'static str,
+}
+
+impl RelaySocket {
+    pub fn env(&self, dir: &Path) -> String {
+        format!("{}{}", self.env_prefix, dir.join(self.name).display())
+    }
+
+    /// Argument of the `ego relay` subcommand
+    pub fn to_arg(&self) -> String {
+        format!("{}={}", self.name, self.target.display())
+    }
+}
+
+/// Private per-session relay directory, e.g. `/tmp/ego-relay-1234`
+pub fn relay_dir() -> PathBuf {
+    env::temp_dir().join(format!("ego-relay-{}", process::id()))
+}
+
+/// Name of the relayed accessibility bus socket
+pub const A11Y_BUS: &str = "at-spi-bus";
+/// Name of the relayed `IBus` input method bus socket
+pub const IBUS_BUS: &str = "ibus";
+/// Relayed sockets that speak D-Bus, whose authentication is rewritten, see [`relay_dbus_auth`].
+const DBUS_SOCKETS: [&str; 2] = [A11Y_BUS, IBUS_BUS];
+
+/// Extract socket path from a D-Bus address like `unix:path=/run/user/1000/at-spi/bus_0,guid=..`.
+/// Returns `None` for other transports like abstract sockets.
+pub fn parse_bus_address(address: &str) -> Option<PathBuf> {
+    let params = address.split(';').next()?.strip_prefix("unix:")?;
+    params
+        .split(',')
+        .find_map(|param| param.strip_prefix("path="))
+        .map(PathBuf::from)
+}
+
+/// Display and audio sockets of the invoking user's session that exist and should be relayed.
+pub fn display_sockets(ctx: &EgoContext) -> Result<Vec<RelaySocket>, EgoError> {
+    let mut sockets = Vec::new();
+    if let Some(target) = get_wayland_socket(ctx).map_err(EgoError::Wayland)? {
+        sockets.push(RelaySocket { name: "wayland-0", target, env_prefix: "WAYLAND_DISPLAY=" });
+    }
+    let target = ctx.runtime_dir.join("pulse/native");
+    if target.exists() {
+        sockets.push(RelaySocket {
+            name: "pulse-native",
+            target,
+            env_prefix: "PULSE_SERVER=unix:",
+        });
+    }
+    let target = ctx.runtime_dir.join("pipewire-0");
+    if target.exists() {
+        sockets.push(RelaySocket { name: "pipewire-0", target, env_prefix: "PIPEWIRE_REMOTE=" });
+    }
+    Ok(sockets)
+}
+
+/// Create the relay dir and grant target user access to it. The dir may already have been created
+/// for other relayed sockets by this process.
+pub fn create_relay_dir(ctx: &EgoContext, grant: &mut Grant) -> Result<PathBuf, AnyErr> {
+    let dir = relay_dir();
+    if !ctx.dry_run {
+        if let Err(err) = DirBuilder::new().mode(0o700).create(&dir) {
+            // Only reuse our own private dir
+            let meta = fs::symlink_metadata(&dir)?;
+            if err.kind() != ErrorKind::AlreadyExists
+                || !meta.is_dir()
+                || meta.uid() != Uid::current().as_raw()
+                || meta.mode() & 0o077 != 0
+            {
+                bail!("'{}': {err}", dir.display());
+            }
+            return Ok(dir);
+        }
+    }
+    grant.add_acl(ctx, &dir, ACL_EXECUTE)?;
+    Ok(dir)
+}
+
+/// Block until process `parent` exits. When the command exits, sudo or machinectl exits, and the
+/// helper process is re-parented.
+pub fn wait_for_parent(parent: u32) {
+    while parent_id() == parent {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Start ego subcommand `args` as a helper process and wait until it listens on all `sockets`.
+pub fn spawn_helper(args: &[String], sockets: &[PathBuf]) -> Result<(), AnyErr> {
+    debug!("Starting helper: ego {}", shell_words::join(args));
+    // Own process group, so that Ctrl+C in terminal goes to the command, not the helper
+    let mut child = Command::new(env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    for _ in 0..50 {
+        if sockets.iter().all(|path| path.exists()) {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            bail!("Helper process 'ego {}' exited with {status}", args[0]);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    bail!("Timed out waiting for helper sockets: {}", sockets[0].display())
+}
+
+/// Start the relay process and wait until it listens on all sockets.
+fn spawn_relay(dir: &Path, sockets: &[RelaySocket]) -> Result<(), AnyErr> {
+    let dir_arg = require_with!(dir.to_str(), "Path '{}' is not valid UTF-8", dir.display());
+    let mut args = vec![
+        "relay".to_string(),
+        "--parent".into(),
+        process::id().to_string(),
+    ];
+    args.push(dir_arg.to_string());
+    args.extend(sockets.iter().map(RelaySocket::to_arg));
+    let paths: Vec<PathBuf> = sockets.iter().map(|s| dir.join(s.name)).collect();
+    spawn_helper(&args, &paths)
+}
+
+/// Start relay for `sockets` and grant the target user access to them. The invoking user's runtime
+/// dir is not touched, only the PulseAudio cookie is copied to the relay dir.
+pub fn start_relay(ctx: &EgoContext, sockets: &[RelaySocket]) -> Result<Grant, EgoError> {
+    let mut grant = Grant::default();
+    let dir = create_relay_dir(ctx, &mut grant).map_err(EgoError::RuntimeDir)?;
+
+    if ctx.dry_run {
+        for socket in sockets {
+            let detail =
+                format!("{} -> {}", dir.join(socket.name).display(), socket.target.display());
+            print_dry_run("relay", detail);
+        }
+    } else {
+        spawn_relay(&dir, sockets).map_err(EgoError::RuntimeDir)?;
+    }
+
+    for socket in sockets {
+        let err_class = match socket.name {
+            "wayland-0" => EgoError::Wayland,
+            A11Y_BUS | IBUS_BUS => EgoError::Integration,
+            "ssh-agent" | "gpg-agent" => EgoError::Agent,
+            _ => EgoError::Audio,
+        };
+        let path = dir.join(socket.name);
+        grant
+            .add_acl(ctx, &path, ACL_READ | ACL_WRITE)
+            .map_err(err_class)?;
+        grant.env.push(socket.env(&dir));
+    }
+
+    if sockets.iter().any(|s| s.name == "pulse-native") {
+        grant.extend(relay_pulseaudio_cookie(ctx, &dir).map_err(EgoError::Audio)?);
+    }
+    Ok(grant)
+}
+
+fn relay_pulseaudio_cookie(ctx: &EgoContext, dir: &Path) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let cookie_path = find_pulseaudio_cookie()?;
+    let target_path = dir.join("pulse-cookie");
+    if ctx.dry_run {
+        let detail = format!("copy {} to {}", cookie_path.display(), target_path.display());
+        print_dry_run("file", detail);
+    } else {
+        fs::copy(&cookie_path, &target_path)?;
+    }
+    grant.add_acl(ctx, &target_path, ACL_READ)?;
+    grant
+        .env
+        .push(format!("PULSE_COOKIE={}", target_path.display()));
+    Ok(grant)
+}
+
+/// Copy data and file descriptors from `from` to `to` until end of stream.
+pub fn forward(from: &UnixStream, to: &UnixStream) -> Result<(), AnyErr> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut cmsg_buf = cmsg_space!([RawFd; MAX_FDS]);
+    loop {
+        let mut fds: Vec<OwnedFd> = Vec::new();
+        let len = {
+            let mut iov = [IoSliceMut::new(&mut buf)];
+            let msg = recvmsg::<()>(
+                from.as_raw_fd(),
+                &mut iov,
+                Some(cmsg_buf.as_mut_slice()),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+            for cmsg in msg.cmsgs()? {
+                if let ControlMessageOwned::ScmRights(received) = cmsg {
+                    // SAFETY: received file descriptors are new and owned by us
+                    fds.extend(
+                        received
+                            .into_iter()
+                            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                    );
+                }
+            }
+            msg.bytes
+        };
+        if len == 0 {
+            break;
+        }
+
+        let raw_fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        let mut sent = 0;
+        while sent < len {
+            // File descriptors are attached to the first chunk only
+            let cmsgs = match sent {
+                0 if !raw_fds.is_empty() => vec![ControlMessage::ScmRights(&raw_fds)],
+                _ => vec![],
+            };
+            let iov = [IoSlice::new(&buf[sent..len])];
+            sent += sendmsg::<()>(to.as_raw_fd(), &iov, &cmsgs, MsgFlags::MSG_NOSIGNAL, None)?;
+        }
+    }
+    to.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Rewrite UID in a D-Bus `AUTH EXTERNAL <hex uid>` or `DATA <hex uid>` line (with optional
+/// leading nul byte) to `uid`. Returns `None` for other lines.
+pub fn rewrite_auth_line(line: &[u8], uid: u32) -> Option<Vec<u8>> {
+    let (nul, rest) = match line.split_first() {
+        Some((0, rest)) => (&line[..1], rest),
+        _ => (&line[..0], line),
+    };
+    let rest = rest.strip_suffix(b"\r\n")?;
+    let command = [b"AUTH EXTERNAL ".as_slice(), b"DATA "]
+        .into_iter()
+        .find(|prefix| rest.starts_with(prefix))?;
+    let hex_uid = uid.to_string().bytes().fold(String::new(), |mut out, b| {
+        write!(out, "{b:02x}").unwrap();
+        out
+    });
+    Some([nul, command, hex_uid.as_bytes(), b"\r\n"].concat())
+}
+
+/// Relay the D-Bus authentication phase from `client` to `server`. The bus daemon checks the UID
+/// claimed by the client against the peer credentials of the connection, which are the relay's.
+fn relay_dbus_auth(mut client: &UnixStream, mut server: &UnixStream) -> Result<(), AnyErr> {
+    let uid = Uid::current().as_raw();
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        while let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line: Vec<u8> = buf.drain(..pos + 2).collect();
+            server.write_all(&rewrite_auth_line(&line, uid).unwrap_or_else(|| line.clone()))?;
+            if line
+                .strip_prefix(b"\0")
+                .unwrap_or(&line)
+                .starts_with(b"BEGIN")
+            {
+                // Binary protocol follows
+                server.write_all(&buf)?;
+                return Ok(());
+            }
+        }
+        let len = client.read(&mut chunk)?;
+        if len == 0 {
+            server.write_all(&buf)?;
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+fn relay_connection(client: &UnixStream, target: &Path, dbus_auth: bool) -> Result<(), AnyErr> {
+    let server = UnixStream::connect(target)?;
+    let (server_read, client_write) = (server.try_clone()?, client.try_clone()?);
+    // Errors are not Send, only pass the message back
+    let downstream =
+        thread::spawn(move || forward(&server_read, &client_write).map_err(|err| err.to_string()));
+    if dbus_auth {
+        relay_dbus_auth(client, &server)?;
+    }
+    let ret = forward(client, &server);
+    if let Ok(Err(err)) = downstream.join() {
+        debug!("Relay from '{}': {err}", target.display());
+    }
+    ret
+}
+
+fn accept_loop(listener: &UnixListener, target: &Path, dbus_auth: bool) {
+    for client in listener.incoming() {
+        match client {
+            Ok(client) => {
+                let target = target.to_path_buf();
+                thread::spawn(move || {
+                    if let Err(err) = relay_connection(&client, &target, dbus_auth) {
+                        debug!("Relay from '{}': {err}", target.display());
+                    }
+                });
+            }
+            Err(err) => debug!("Relay accept failed: {err}"),
+        }
+    }
+}
+
+/// `ego relay`: listen on sockets in `dir` and relay connections until `parent` exits, then remove
+/// `dir`. Sockets are given as `NAME=TARGET`.
+pub fn run_relay(parent: u32, dir: &Path, sockets: &[String]) -> Result<(), AnyErr> {
+    let mut listeners = Vec::new();
+    for spec in sockets {
+        let Some((name, target)) = spec.split_once('=') else {
+            bail!("Invalid relay socket '{spec}', expected NAME=TARGET");
+        };
+        if name.contains('/') {
+            bail!("Invalid relay socket name '{name}'");
+        }
+        let listener = UnixListener::bind(dir.join(name))?;
+        listeners.push((listener, PathBuf::from(target), DBUS_SOCKETS.contains(&name)));
+    }
+    for (listener, target, dbus_auth) in listeners {
+        thread::spawn(move || accept_loop(&listener, &target, dbus_auth));
+    }
+
+    wait_for_parent(parent);
+    debug!("Parent exited, stopping relay");
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
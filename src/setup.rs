@@ -0,0 +1,222 @@
+//! `ego setup`: provision the target user, sudoers and polkit rules from the `varia/` templates.
+
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::getenv_optional;
+use crate::util::{have_command, run_command};
+use log::{info, warn};
+use nix::unistd::{Group, Uid, User};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Drop-in file rendered from a template
+pub struct SetupFile {
+    pub path: PathBuf,
+    pub content: String,
+    pub mode: u32,
+}
+
+/// Replace whole-word occurrences of `word`. Word characters are alphanumerics, `_` and `-`, so
+/// that `ego` and `ego-users` are replaced independently.
+pub fn replace_word(content: &str, word: &str, replacement: &str) -> String {
+    let is_word_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(pos) = rest.find(word) {
+        let before = rest[..pos].chars().next_back();
+        let after = rest[pos + word.len()..].chars().next();
+        out.push_str(&rest[..pos]);
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            out.push_str(word);
+        } else {
+            out.push_str(replacement);
+        }
+        rest = &rest[pos + word.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Render a `varia/` template for the given user and group names. The `ego` executable in command
+/// paths like `/usr/bin/ego` keeps its name.
+fn render(template: &str, user: &str, group: &str) -> String {
+    let template = template.replace("bin/ego ", "bin/\0 ");
+    replace_word(&replace_word(&template, "ego-users", group), "ego", user)
+        .replace("bin/\0 ", "bin/ego ")
+}
+
+/// Base name of the drop-in files for `user`, e.g. `ego` or `ego-alter`
+pub fn drop_in_name(user: &str) -> String {
+    if user == "ego" { "ego".to_string() } else { format!("ego-{user}") }
+}
+
+/// All drop-in files for `user` and `group`, with their install locations.
+pub fn setup_files(user: &str, group: &str) -> Vec<SetupFile> {
+    let name = drop_in_name(user);
+    let file = |path: String, template: &str, mode| SetupFile {
+        path: path.into(),
+        content: render(template, user, group),
+        mode,
+    };
+    vec![
+        file(
+            format!("/etc/sysusers.d/{name}.conf"),
+            include_str!("../varia/ego.sysusers.conf"),
+            0o644,
+        ),
+        file(
+            format!("/etc/tmpfiles.d/{name}.conf"),
+            include_str!("../varia/ego.tmpfiles.conf"),
+            0o644,
+        ),
+        file(format!("/etc/sudoers.d/50_{name}"), include_str!("../varia/ego.sudoers"), 0o440),
+        file(
+            format!("/etc/polkit-1/rules.d/50-{name}.rules"),
+            include_str!("../varia/ego.rules"),
+            0o644,
+        ),
+    ]
+}
+
+/// Run command, or just print it in dry-run mode.
+fn run_step(dry_run: bool, program: &str, args: &[&str]) -> Result<(), AnyErr> {
+    let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+    if dry_run {
+        println!("# Would run: {program} {}", shell_words::join(&args));
+    } else {
+        warn!("Running: {program} {}", shell_words::join(&args));
+        run_command(program, &args)?;
+    }
+    Ok(())
+}
+
+/// Install `file` unless it already has identical content.
+fn install_file(file: &SetupFile) -> Result<(), AnyErr> {
+    let parent = file.path.parent().unwrap_or(Path::new("/"));
+    if !parent.is_dir() {
+        warn!(
+            "Skipping '{}': directory '{}' does not exist",
+            file.path.display(),
+            parent.display()
+        );
+        return Ok(());
+    }
+    if fs::read_to_string(&file.path).is_ok_and(|old| old == file.content) {
+        info!("'{}' is up to date", file.path.display());
+        return Ok(());
+    }
+    if is_sudoers(file) && !have_command("visudo") {
+        warn!("Skipping '{}': visudo not found to validate it", file.path.display());
+        return Ok(());
+    }
+
+    // Write to a temporary file and rename, so that a broken or partial file is never in place.
+    // sudo ignores files in sudoers.d whose name contains a '.'.
+    let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = parent.join(format!(".{name}.tmp"));
+    fs::write(&tmp, &file.content)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(file.mode))?;
+    if let Err(err) = validate_file(file, &tmp) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::rename(&tmp, &file.path)?;
+    warn!("Installed '{}'", file.path.display());
+    Ok(())
+}
+
+fn is_sudoers(file: &SetupFile) -> bool {
+    file.path.starts_with("/etc/sudoers.d")
+}
+
+/// Check syntax of sudoers drop-ins with `visudo`, a broken file would lock out sudo entirely.
+fn validate_file(file: &SetupFile, tmp: &Path) -> Result<(), AnyErr> {
+    if !is_sudoers(file) {
+        return Ok(());
+    }
+    run_command("visudo", &["-cf".into(), tmp.to_string_lossy().into()])?;
+    Ok(())
+}
+
+/// Name of the user who invoked `sudo ego setup` or `pkexec ego setup`.
+fn invoking_user() -> Result<Option<String>, AnyErr> {
+    if let Some(user) = getenv_optional("SUDO_USER")? {
+        return Ok(Some(user));
+    }
+    if let Some(uid) = getenv_optional("PKEXEC_UID")? {
+        let uid = Uid::from_raw(uid.parse()?);
+        return Ok(User::from_uid(uid)?.map(|u| u.name));
+    }
+    Ok(None)
+}
+
+fn create_user(files: &[SetupFile], user: &str, group: &str, dry_run: bool) -> Result<(), AnyErr> {
+    if User::from_name(user)?.is_some() {
+        info!("User '{user}' already exists");
+    } else if have_command("systemd-sysusers") {
+        run_step(dry_run, "systemd-sysusers", &[&files[0].path.to_string_lossy()])?;
+    } else {
+        if Group::from_name(group)?.is_none() {
+            run_step(dry_run, "groupadd", &["--system", group])?;
+        }
+        run_step(dry_run, "useradd", &["--system", "--create-home", user])?;
+    }
+
+    // Home directory is created by tmpfiles.d if missing
+    if have_command("systemd-tmpfiles") {
+        run_step(dry_run, "systemd-tmpfiles", &["--create", &files[1].path.to_string_lossy()])?;
+    }
+    Ok(())
+}
+
+fn add_to_group(group: &str, dry_run: bool) -> Result<(), AnyErr> {
+    let Some(member) = invoking_user()? else {
+        warn!("Could not detect invoking user, add users to group '{group}' manually");
+        return Ok(());
+    };
+    if Group::from_name(group)?.is_some_and(|g| g.mem.contains(&member)) {
+        info!("User '{member}' is already in group '{group}'");
+        return Ok(());
+    }
+    run_step(dry_run, "usermod", &["--append", "--groups", group, &member])?;
+    if !dry_run {
+        warn!("Added '{member}' to group '{group}', log out and back in for it to take effect");
+    }
+    Ok(())
+}
+
+/// Provision target `user` and `group` and install drop-in files. Safe to run multiple times.
+pub fn run_setup(user: &str, group: &str, dry_run: bool) -> Result<(), AnyErr> {
+    for name in [user, group] {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-".contains(c))
+        {
+            bail!("Invalid user or group name '{name}'");
+        }
+    }
+    let files = setup_files(user, group);
+
+    if dry_run {
+        for file in &files {
+            println!("# {} (mode {:o})\n{}", file.path.display(), file.mode, file.content);
+        }
+    } else {
+        if !Uid::effective().is_root() {
+            return Err(ErrorWithHint::new(
+                "ego setup must be run as root".into(),
+                "Try: sudo ego setup (or use --dry-run to preview)".into(),
+            )
+            .into());
+        }
+        for file in &files {
+            install_file(file)?;
+        }
+    }
+
+    create_user(&files, user, group, dry_run)?;
+    add_to_group(group, dry_run)?;
+    Ok(())
+}
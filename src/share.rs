@@ -0,0 +1,613 @@
+//! Sharing files and directory trees with the target user (`--share`, `--unshare`).
+//!
+//! Shares are recorded in `$XDG_STATE_HOME/ego/shares`, so they can be listed and revoked later.
+//! Parent directories that were given traverse permission are recorded in
+//! `$XDG_STATE_HOME/ego/traverse`, so that only permissions added by ego are removed again.
+
+use crate::cli::{ShareMode, ShareSpec};
+use crate::device::{is_device_node, revoke_device};
+use crate::errors::{AnyErr, ErrorWithHint};
+use crate::relay::{spawn_helper, wait_for_parent};
+use crate::util::{escape_field, unescape_field};
+use crate::{
+    EgoContext, Grant, acl_perm_str, add_file_acl, clear_file_acl, ensure_ego_statedir,
+    is_permission_denied, parse_acl_perms, print_dry_run, remove_file_acl,
+};
+use log::{debug, info, warn};
+use nix::libc::uid_t;
+use nix::unistd::Uid;
+use posix_acl::{ACL_EXECUTE, ACL_READ, ACL_WRITE, PosixACL, Qualifier};
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind::NotFound;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+fn escape_path(path: &Path) -> String {
+    escape_field(path.as_os_str().as_bytes())
+}
+
+fn unescape_path(field: &str) -> PathBuf {
+    PathBuf::from(OsString::from_vec(unescape_field(field)))
+}
+
+/// A path shared with a target user
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShareRecord {
+    pub uid: uid_t,
+    pub user: String,
+    pub mode: ShareMode,
+    pub path: PathBuf,
+}
+
+impl ShareRecord {
+    /// Records are stored as tab-separated lines: `uid user mode path`, with the path escaped
+    pub fn parse(line: &str) -> Option<ShareRecord> {
+        let mut parts = line.splitn(4, '\t');
+        let uid = parts.next()?.parse().ok()?;
+        let user = parts.next()?.to_string();
+        let mode = match parts.next()? {
+            "ro" => ShareMode::ReadOnly,
+            "rw" => ShareMode::ReadWrite,
+            _ => return None,
+        };
+        let path = unescape_path(parts.next()?);
+        Some(ShareRecord { uid, user, mode, path })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\t{}", self.uid, self.user, self.mode.as_str(), escape_path(&self.path))
+    }
+}
+
+/// Parent directory that was given traverse permission for a share
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraverseRecord {
+    pub uid: uid_t,
+    pub path: PathBuf,
+}
+
+impl TraverseRecord {
+    /// Records are stored as tab-separated lines: `uid path`
+    pub fn parse(line: &str) -> Option<TraverseRecord> {
+        let (uid, path) = line.split_once('\t')?;
+        Some(TraverseRecord { uid: uid.parse().ok()?, path: unescape_path(path) })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{}\t{}", self.uid, escape_path(&self.path))
+    }
+}
+
+/// Read state file `name`, missing file is empty.
+fn read_state_file(name: &str) -> Result<String, AnyErr> {
+    let path = ensure_ego_statedir()?.join(name);
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == NotFound => Ok(String::new()),
+        Err(err) => bail!("'{}': {err}", path.display()),
+    }
+}
+
+fn load_records() -> Result<Vec<ShareRecord>, AnyErr> {
+    let content = read_state_file("shares")?;
+    Ok(content.lines().filter_map(ShareRecord::parse).collect())
+}
+
+fn save_records(records: &[ShareRecord]) -> Result<(), AnyErr> {
+    let content: String = records.iter().map(|r| r.to_line() + "\n").collect();
+    fs::write(ensure_ego_statedir()?.join("shares"), content)?;
+    Ok(())
+}
+
+fn load_traverse() -> Result<Vec<TraverseRecord>, AnyErr> {
+    let content = read_state_file("traverse")?;
+    Ok(content.lines().filter_map(TraverseRecord::parse).collect())
+}
+
+fn save_traverse(records: &[TraverseRecord]) -> Result<(), AnyErr> {
+    let content: String = records.iter().map(|r| r.to_line() + "\n").collect();
+    fs::write(ensure_ego_statedir()?.join("traverse"), content)?;
+    Ok(())
+}
+
+/// ACL permissions for a file or directory in shared tree.
+fn share_perms(mode: ShareMode, is_dir: bool, file_mode: u32) -> u32 {
+    let mut perms = match mode {
+        ShareMode::ReadOnly => ACL_READ,
+        ShareMode::ReadWrite => ACL_READ | ACL_WRITE,
+    };
+    // Directories need execute for traversal, files only if executable by owner.
+    if is_dir || file_mode & 0o100 != 0 {
+        perms |= ACL_EXECUTE;
+    }
+    perms
+}
+
+/// Add user entry to directory default ACL, so newly created files are also shared.
+fn add_default_acl(path: &Path, uid: u32, flags: u32, dir_mode: u32) -> Result<(), AnyErr> {
+    let mut acl = PosixACL::read_default_acl(path)?;
+    if acl.entries().is_empty() {
+        // No default ACL yet, base entries are derived from directory mode like `setfacl -d` does.
+        acl = PosixACL::new(dir_mode);
+    }
+    acl.set(Qualifier::User(uid), flags);
+    acl.write_default_acl(path)?;
+    Ok(())
+}
+
+fn remove_default_acl(path: &Path, uid: u32) -> Result<(), AnyErr> {
+    let mut acl = PosixACL::read_default_acl(path)?;
+    if acl.remove(Qualifier::User(uid)).is_some() {
+        acl.write_default_acl(path)?;
+    }
+    Ok(())
+}
+
+/// Recursively grant ACLs on `path`. Symlinks are not followed.
+fn grant_tree(path: &Path, uid: u32, mode: ShareMode) -> Result<(), AnyErr> {
+    let meta = path.symlink_metadata()?;
+    if meta.file_type().is_symlink() {
+        debug!("Share: skipping symlink '{}'", path.display());
+        return Ok(());
+    }
+    let perms = share_perms(mode, meta.is_dir(), meta.mode());
+    add_file_acl(path, uid, perms)?;
+    if meta.is_dir() {
+        add_default_acl(path, uid, perms, meta.mode())?;
+        for entry in fs::read_dir(path)? {
+            grant_tree(&entry?.path(), uid, mode)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively remove user entries from ACLs of `path`.
+fn revoke_tree(path: &Path, uid: u32) -> Result<(), AnyErr> {
+    let meta = match path.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == NotFound => return Ok(()),
+        Err(err) => bail!("'{}': {err}", path.display()),
+    };
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    remove_file_acl(path, uid)?;
+    if meta.is_dir() {
+        remove_default_acl(path, uid)?;
+        for entry in fs::read_dir(path)? {
+            revoke_tree(&entry?.path(), uid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parent directories owned by current user. We cannot change ACLs of other parents anyway.
+fn owned_parents(path: &Path) -> impl Iterator<Item = &Path> {
+    let uid = Uid::current().as_raw();
+    path.ancestors()
+        .skip(1)
+        .filter(move |dir| dir.metadata().is_ok_and(|meta| meta.uid() == uid))
+}
+
+/// Permissions of target user `uid` on `path` with file mode `mode`: its own ACL entry, if any, and
+/// the effective permissions, which come from the "other" class if there is no entry.
+fn target_access(path: &Path, mode: u32, uid: uid_t) -> Result<(Option<u32>, u32), AnyErr> {
+    let entry = PosixACL::read_acl(path)?.get(Qualifier::User(uid));
+    Ok((entry, entry.unwrap_or(mode & 0o007)))
+}
+
+/// Add traverse (execute) permission to parent directories of `path`. Returns the directories that
+/// did not have it yet.
+pub fn grant_parents(
+    ctx: &EgoContext,
+    path: &Path,
+    grant: &mut Grant,
+) -> Result<Vec<PathBuf>, AnyErr> {
+    let mut added = Vec::new();
+    for dir in owned_parents(path) {
+        if ctx.relay && dir == ctx.runtime_dir {
+            return Err(ErrorWithHint::new(
+                format!("'{}' is in the runtime dir, which --relay keeps private", path.display()),
+                "Share a path outside the runtime dir, or omit --relay".into(),
+            )
+            .into());
+        }
+        let (entry, access) = target_access(dir, dir.metadata()?.mode(), ctx.target_uid)?;
+        if access & ACL_EXECUTE == 0 {
+            grant.add_acl(ctx, dir, entry.unwrap_or(0) | ACL_EXECUTE)?;
+            debug!("Share: added traverse permission to '{}'", dir.display());
+            added.push(dir.to_path_buf());
+        }
+    }
+    Ok(added)
+}
+
+/// Record parent directories that were given traverse permission for a share.
+fn record_traverse(ctx: &EgoContext, dirs: Vec<PathBuf>) -> Result<(), AnyErr> {
+    let mut records = load_traverse()?;
+    for path in dirs {
+        let record = TraverseRecord { uid: ctx.target_uid, path };
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+    save_traverse(&records)
+}
+
+/// Add share to the records file, replacing any previous record of the same path and user.
+pub fn record_share(ctx: &EgoContext, path: &Path, mode: ShareMode) -> Result<(), AnyErr> {
+    let mut records = load_records()?;
+    records.retain(|r| !(r.uid == ctx.target_uid && r.path == path));
+    records.push(ShareRecord {
+        uid: ctx.target_uid,
+        user: ctx.target_user.clone(),
+        mode,
+        path: path.to_path_buf(),
+    });
+    save_records(&records)
+}
+
+/// Grant target user access to the path in `spec` and record it.
+pub fn share_path(ctx: &EgoContext, spec: &ShareSpec) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let path =
+        fs::canonicalize(&spec.path).map_err(|err| format!("'{}': {err}", spec.path.display()))?;
+
+    if ctx.dry_run {
+        let detail = format!(
+            "{} ({}, recursive) with {}",
+            path.display(),
+            spec.mode.as_str(),
+            ctx.target_user
+        );
+        print_dry_run("share", detail);
+        grant_parents(ctx, &path, &mut grant)?;
+        return Ok(grant);
+    }
+    grant_tree(&path, ctx.target_uid, spec.mode)
+        .map_err(|err| format!("'{}': {err}", path.display()))?;
+    let traverse = grant_parents(ctx, &path, &mut grant)?;
+    record_share(ctx, &path, spec.mode)?;
+    record_traverse(ctx, traverse)?;
+    grant
+        .resources
+        .push(format!("share:{}:{}", spec.mode.as_str(), path.display()));
+
+    info!("Shared '{}' ({}) with user {}", path.display(), spec.mode.as_str(), ctx.target_user);
+    Ok(grant)
+}
+
+/// Grant target user access to command arguments that refer to existing files (`--grant-args`).
+/// Directories are not granted recursively. Files that the target user can already access are
+/// skipped, files of other users only cause a warning.
+///
+/// Granted arguments are replaced with absolute paths, because the target user's command does not
+/// run in our working directory. Symlinks in them are kept as given.
+///
+/// Unlike shares, the permissions are not kept: an `ego revoke` helper removes them again when
+/// the command exits. They are recorded in `$XDG_STATE_HOME/ego/grants` while held, so that
+/// concurrent launches needing the same files do not revoke each other's permissions.
+pub fn grant_args(ctx: &EgoContext, args: &mut [String], mode: ShareMode) -> Result<Grant, AnyErr> {
+    let mut grant = Grant::default();
+    let mut granted = Vec::new();
+    let mut revoke = Vec::new();
+    let held = live_held()?;
+
+    for arg in args.iter_mut() {
+        if arg.starts_with('-') {
+            continue;
+        }
+        let Ok(path) = fs::canonicalize(arg.as_str()) else {
+            continue;
+        };
+        let meta = path.metadata()?;
+        debug!("Argument '{arg}' is '{}'", path.display());
+
+        let needed = share_perms(mode, meta.is_dir(), meta.mode());
+        let (entry, access) = target_access(&path, meta.mode(), ctx.target_uid)?;
+        let mut acls = Vec::new();
+        if access & needed == needed {
+            debug!("'{}' is already accessible to user {}", path.display(), ctx.target_user);
+        } else if meta.uid() != Uid::current().as_raw() {
+            warn!("Not granting access to '{}', it is owned by another user", path.display());
+            continue;
+        } else {
+            let entry = entry.unwrap_or(0);
+            match grant.add_acl(ctx, &path, entry | needed) {
+                Ok(()) => acls.push((needed & !entry, path.clone())),
+                Err(err) => {
+                    warn!("Not granting access to '{}': {err}", path.display());
+                    continue;
+                }
+            }
+        }
+        for dir in grant_parents(ctx, &path, &mut grant)? {
+            acls.push((ACL_EXECUTE, dir));
+        }
+        adopt_held(ctx, &held, &path, &mut acls);
+        if !acls.is_empty() {
+            let absolute = std::path::absolute(arg.as_str())?;
+            *arg = require_with!(
+                absolute.to_str(),
+                "Path '{}' is not valid UTF-8",
+                absolute.display()
+            )
+            .to_string();
+            granted.push(arg.clone());
+        }
+        revoke.extend(acls);
+    }
+
+    if !granted.is_empty() {
+        warn!(
+            "Granted user {} {} access to: {}",
+            ctx.target_user,
+            mode.as_str(),
+            shell_words::join(&granted)
+        );
+    }
+    if !revoke.is_empty() {
+        revoke_on_exit(ctx, &revoke)?;
+    }
+    Ok(grant)
+}
+
+/// Permissions granted for a single launch, like its arguments or agent sockets, held until launch
+/// process `pid` exits
+#[derive(Debug, PartialEq, Eq)]
+pub struct HeldGrant {
+    pub pid: u32,
+    pub uid: uid_t,
+    pub perms: u32,
+    pub path: PathBuf,
+}
+
+impl HeldGrant {
+    /// Records are stored as tab-separated lines: `pid uid perms path`, with the path escaped
+    pub fn parse(line: &str) -> Option<HeldGrant> {
+        let mut parts = line.splitn(4, '\t');
+        Some(HeldGrant {
+            pid: parts.next()?.parse().ok()?,
+            uid: parts.next()?.parse().ok()?,
+            perms: parse_acl_perms(parts.next()?)?,
+            path: unescape_path(parts.next()?),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.pid,
+            self.uid,
+            acl_perm_str(self.perms),
+            escape_path(&self.path)
+        )
+    }
+
+    fn is_live(&self) -> bool {
+        Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+}
+
+fn load_held() -> Result<Vec<HeldGrant>, AnyErr> {
+    let content = read_state_file("grants")?;
+    Ok(content.lines().filter_map(HeldGrant::parse).collect())
+}
+
+fn save_held(records: &[HeldGrant]) -> Result<(), AnyErr> {
+    let content: String = records.iter().map(|r| r.to_line() + "\n").collect();
+    fs::write(ensure_ego_statedir()?.join("grants"), content)?;
+    Ok(())
+}
+
+/// Grants of other launches that are still running.
+pub fn live_held() -> Result<Vec<HeldGrant>, AnyErr> {
+    let mut records = load_held()?;
+    records.retain(|r| r.pid != process::id() && r.is_live());
+    Ok(records)
+}
+
+/// Add permissions on `path` and its parent directories that other running launches hold to
+/// `acls`, so that they are released by whichever launch exits last.
+pub fn adopt_held(
+    ctx: &EgoContext,
+    held: &[HeldGrant],
+    path: &Path,
+    acls: &mut Vec<(u32, PathBuf)>,
+) {
+    for held in held {
+        let adopt = held.uid == ctx.target_uid
+            && path.starts_with(&held.path)
+            && !acls.iter().any(|(_, p)| *p == held.path);
+        if adopt {
+            acls.push((held.perms, held.path.clone()));
+        }
+    }
+}
+
+/// Record `acls` as held by this process and start an `ego revoke` helper that removes them when
+/// we exit. Records of launches that have exited are dropped.
+pub fn revoke_on_exit(ctx: &EgoContext, acls: &[(u32, PathBuf)]) -> Result<(), AnyErr> {
+    if !ctx.dry_run {
+        let mut records = load_held()?;
+        records.retain(HeldGrant::is_live);
+        records.extend(acls.iter().map(|(perms, path)| HeldGrant {
+            pid: process::id(),
+            uid: ctx.target_uid,
+            perms: *perms,
+            path: path.clone(),
+        }));
+        save_held(&records)?;
+    }
+    spawn_revoke(ctx, acls)
+}
+
+/// Start `ego revoke` helper that removes `acls` when we exit.
+fn spawn_revoke(ctx: &EgoContext, acls: &[(u32, PathBuf)]) -> Result<(), AnyErr> {
+    let mut args = vec![
+        "revoke".to_string(),
+        "--parent".into(),
+        process::id().to_string(),
+        "--uid".into(),
+        ctx.target_uid.to_string(),
+    ];
+    args.extend(
+        acls.iter()
+            .map(|(flags, path)| format!("{}:{}", acl_perm_str(*flags), path.display())),
+    );
+    if ctx.dry_run {
+        print_dry_run("revoke", format_args!("ego {}", shell_words::join(&args)));
+        return Ok(());
+    }
+    spawn_helper(&args, &[])
+}
+
+/// Parse `ego revoke` argument `PERMS:PATH`, e.g. `r--:/home/me/file.pdf`.
+pub fn parse_acl_spec(spec: &str) -> Option<(u32, PathBuf)> {
+    let (perms, path) = spec.split_once(':')?;
+    Some((parse_acl_perms(perms)?, PathBuf::from(path)))
+}
+
+/// `ego revoke`: when process `parent` exits, clear ACL permissions of target user `uid` given as
+/// `PERMS:PATH`. Permissions that recorded shares or other running launches rely on are kept.
+pub fn run_revoke(parent: u32, uid: uid_t, specs: &[String]) -> Result<(), AnyErr> {
+    let mut acls = Vec::new();
+    for spec in specs {
+        let acl = parse_acl_spec(spec);
+        acls.push(require_with!(acl, "Invalid ACL '{spec}', expected PERMS:PATH"));
+    }
+
+    wait_for_parent(parent);
+    let records = load_records()?;
+    let mut grants = load_held()?;
+    grants.retain(|r| r.pid != parent && r.is_live());
+    save_held(&grants)?;
+    for (flags, path) in acls {
+        let shared = records
+            .iter()
+            .any(|r| r.uid == uid && (r.path.starts_with(&path) || path.starts_with(&r.path)));
+        if shared {
+            debug!("Keeping '{}', it is shared", path.display());
+        } else if grants.iter().any(|r| r.uid == uid && r.path == path) {
+            debug!("Keeping '{}', another launch still uses it", path.display());
+        } else if let Err(err) = clear_file_acl(&path, uid, flags) {
+            debug!("Revoking '{}': {err}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The innermost share of `uid` in `records` that contains `path`.
+pub fn covering_share<'a>(
+    records: &'a [ShareRecord],
+    uid: uid_t,
+    path: &Path,
+) -> Option<&'a ShareRecord> {
+    records
+        .iter()
+        .filter(|r| r.uid == uid && path.starts_with(&r.path))
+        .max_by_key(|r| r.path.components().count())
+}
+
+/// Files of other users need root to revoke.
+fn revoke_error(err: AnyErr, record: &ShareRecord) -> AnyErr {
+    if !is_permission_denied(&err) {
+        return err;
+    }
+    let path = record.path.display();
+    let recursive = if record.path.is_dir() { "-R " } else { "" };
+    ErrorWithHint::new(
+        format!("'{path}': {err}"),
+        format!("Revoke access as root with: sudo setfacl {recursive}-x u:{} {path}", record.user),
+    )
+    .into()
+}
+
+/// Revoke all shares of `path` and clean up traverse permissions that are no longer needed.
+/// Parts of the tree that other shares still cover keep the access those shares give. In dry-run
+/// mode, only print the planned revocations.
+pub fn unshare_path(path: &Path, dry_run: bool) -> Result<(), AnyErr> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (removed, remaining): (Vec<_>, Vec<_>) =
+        load_records()?.into_iter().partition(|r| r.path == path);
+    if removed.is_empty() {
+        bail!("'{}' is not shared", path.display());
+    }
+    let mut traverse = load_traverse()?;
+
+    for record in &removed {
+        let (uid, shown) = (record.uid, record.path.display());
+        match covering_share(&remaining, uid, &record.path) {
+            Some(outer) if dry_run => {
+                print_dry_run(
+                    "unshare",
+                    format_args!(
+                        "{shown} (keep {} from {})",
+                        outer.mode.as_str(),
+                        outer.path.display()
+                    ),
+                );
+            }
+            Some(outer) if record.path.exists() => grant_tree(&record.path, uid, outer.mode)?,
+            Some(_) => {}
+            None if dry_run => {
+                print_dry_run("unshare", format_args!("{shown} (user {})", record.user));
+            }
+            None if is_device_node(&record.path) => {
+                revoke_device(&record.path, &record.user)?;
+            }
+            None => revoke_tree(&record.path, uid).map_err(|err| revoke_error(err, record))?,
+        }
+        // Nested shares were revoked along with the tree
+        for inner in remaining
+            .iter()
+            .filter(|r| r.uid == uid && r.path.starts_with(&record.path) && r.path.exists())
+        {
+            if !dry_run {
+                grant_tree(&inner.path, inner.uid, inner.mode)?;
+            }
+        }
+
+        let mut kept = Vec::new();
+        for dir in traverse {
+            let still_needed = dir.uid != uid
+                || !record.path.starts_with(&dir.path)
+                || remaining
+                    .iter()
+                    .any(|r| r.uid == dir.uid && r.path.starts_with(&dir.path));
+            if still_needed {
+                kept.push(dir);
+            } else if dry_run {
+                print_dry_run("unshare traverse", dir.path.display());
+            } else {
+                clear_file_acl(&dir.path, dir.uid, ACL_EXECUTE)?;
+                debug!("Share: removed traverse permission from '{}'", dir.path.display());
+            }
+        }
+        traverse = kept;
+        if !dry_run {
+            info!("Revoked '{shown}' from user {}", record.user);
+        }
+    }
+    if !dry_run {
+        save_records(&remaining)?;
+        save_traverse(&traverse)?;
+    }
+    Ok(())
+}
+
+/// Paths of all recorded shares
+pub fn shared_paths() -> Result<Vec<PathBuf>, AnyErr> {
+    Ok(load_records()?.into_iter().map(|r| r.path).collect())
+}
+
+/// Print recorded shares to stdout.
+pub fn list_shares() -> Result<(), AnyErr> {
+    for record in load_records()? {
+        println!("{} {} {}", record.user, record.mode.as_str(), record.path.display());
+    }
+    Ok(())
+}
@@ -1,17 +1,52 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
 use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
 use std::sync::OnceLock;
 
 use clap_complete::Generator;
 use clap_complete::shells::{Bash, Fish, Zsh};
 use log::{Level, info};
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
 use snapbox::Assert;
 use snapbox::{Data, file};
 
-use crate::cli::{Method, build_cli, parse_args};
-use crate::util::have_command;
+use crate::agent::GPG_SETUP_SCRIPT;
+use crate::cli::{Action, Method, ShareMode, ShareSpec, build_cli, parse_args, parse_share_spec};
+use crate::cli::{EnvFormat, MessageFormat, NotifyMode};
+use crate::desktop::{DesktopEntry, absolute_file, desktop_id, exec_quote, rewrite_launcher};
+use crate::device::{check_device, class_devices, device_error, resolve_devices};
+use crate::dialog::{confirm_commands, error_commands, error_text, password_commands};
+use crate::doctor::sudo_allows;
+use crate::errors::{AnyErr, EgoError, ErrorWithHint, exit_code};
+use crate::flatpak::{check_installed, session_bus_script, validate_app_id};
+use crate::handoff::{is_handoff_url, shim_script};
+use crate::history::{HistoryEntry, format_timestamp};
+use crate::input_method::{InputMethod, detect_input_method, input_method_env};
+use crate::logging::{journal_field, json_message};
+use crate::machine::{Bind, split_env_path, translate_env, validate_machine_name};
+use crate::notify::{
+    check_session_bus, filter_hints, filter_icon, prefix_summary, proxy_setup_command, strip_images,
+};
+use crate::relay::{RelaySocket, forward, parse_bus_address, rewrite_auth_line};
+use crate::setup::{replace_word, setup_files};
+use crate::share::{HeldGrant, ShareRecord, TraverseRecord, covering_share, parse_acl_spec};
+use crate::util::{have_command, json_string};
 use crate::x11::x11_xcb_add_acl;
-use crate::{EgoContext, check_user_homedir, get_wayland_socket};
+use crate::{
+    EgoContext, RUNTIME_DIR_SCRIPT, SudoRuntimeDir, check_prepare_args, check_user_homedir,
+    format_env, get_wayland_socket, is_permission_denied, setup_script, sudo_runtime_dir,
+};
 
 /// `vec![]` constructor that converts arguments to String
 macro_rules! string_vec {
@@ -76,6 +111,11 @@ fn test_context() -> EgoContext {
         target_uid: 155,
         target_user_shell: "/bin/bash".into(),
         target_user_homedir: "/home/ego".into(),
+        dry_run: false,
+        relay: false,
+        a11y: false,
+        ibus: false,
+        machine: None,
     }
 }
 
@@ -135,6 +175,216 @@ fn test_parse_args() {
     assert_eq!(parse_args(vec!["ego", "-vvvvvv"]).log_level, Level::Trace);
     // --machinectl
     assert_eq!(parse_args(vec!["ego", "--machinectl"]).method, Some(Method::Machinectl));
+    // --dry-run
+    assert!(!parse_args(vec!["ego"]).dry_run);
+    assert!(parse_args(vec!["ego", "--dry-run", "ls"]).dry_run);
+}
+
+#[test]
+fn test_parse_share_spec() {
+    let spec = |path: &str, mode| ShareSpec { path: path.into(), mode };
+
+    assert_eq!(parse_share_spec("/tmp/a"), Ok(spec("/tmp/a", ShareMode::ReadOnly)));
+    assert_eq!(parse_share_spec("/tmp/a:ro"), Ok(spec("/tmp/a", ShareMode::ReadOnly)));
+    assert_eq!(parse_share_spec("/tmp/a:rw"), Ok(spec("/tmp/a", ShareMode::ReadWrite)));
+    // Unknown suffix is part of path
+    assert_eq!(parse_share_spec("/tmp/a:b"), Ok(spec("/tmp/a:b", ShareMode::ReadOnly)));
+    assert!(parse_share_spec(":rw").is_err());
+
+    let args = parse_args(vec!["ego", "--share", "/a", "--share", "/b:rw", "ls"]);
+    assert_eq!(
+        args.share,
+        vec![
+            spec("/a", ShareMode::ReadOnly),
+            spec("/b", ShareMode::ReadWrite)
+        ]
+    );
+    assert_eq!(args.command, string_vec!["ls"]);
+
+    // --grant-args does not consume command
+    let args = parse_args(vec!["ego", "--grant-args", "evince", "a.pdf"]);
+    assert_eq!(args.grant_files, Some(ShareMode::ReadOnly));
+    assert_eq!(args.command, string_vec!["evince", "a.pdf"]);
+    assert_eq!(parse_args(vec!["ego", "--grant-args=rw"]).grant_files, Some(ShareMode::ReadWrite));
+    assert_eq!(parse_args(vec!["ego"]).grant_files, None);
+
+    // Granted arguments are revoked by a helper
+    let args = parse_args(vec!["ego", "revoke", "--parent", "1", "--uid", "155", "r--:/a b"]);
+    assert_eq!(
+        args.action,
+        Some(Action::Revoke { parent: 1, uid: 155, acls: string_vec!["r--:/a b"] })
+    );
+    assert_eq!(parse_acl_spec("rw-:/a:b"), Some((6, PathBuf::from("/a:b"))));
+    assert_eq!(parse_acl_spec("--x:/home/me"), Some((1, PathBuf::from("/home/me"))));
+    assert_eq!(parse_acl_spec("rwx"), None);
+    assert_eq!(parse_acl_spec("xwr:/a"), None);
+}
+
+#[test]
+fn test_share_record() {
+    let record = ShareRecord {
+        uid: 155,
+        user: "ego".into(),
+        mode: ShareMode::ReadWrite,
+        path: "/home/me/Some Dir".into(),
+    };
+    assert_eq!(ShareRecord::parse(&record.to_line()), Some(record));
+    assert_eq!(ShareRecord::parse("garbage"), None);
+
+    let record = TraverseRecord { uid: 155, path: "/home/me".into() };
+    assert_eq!(TraverseRecord::parse(&record.to_line()), Some(record));
+    assert_eq!(TraverseRecord::parse("garbage"), None);
+
+    // Paths with tabs, newlines, backslashes and non-UTF-8 bytes round-trip on a single line
+    let odd_paths = [
+        PathBuf::from("/home/me/tab\there"),
+        PathBuf::from("/home/me/line\nbreak\\x41"),
+        PathBuf::from(OsStr::from_bytes(b"/home/me/latin1-\xe9t\xe9")),
+    ];
+    for path in odd_paths {
+        let record = ShareRecord {
+            uid: 155,
+            user: "ego".into(),
+            mode: ShareMode::ReadOnly,
+            path: path.clone(),
+        };
+        let line = record.to_line();
+        assert!(!line.contains('\n'));
+        assert_eq!(line.matches('\t').count(), 3);
+        assert_eq!(ShareRecord::parse(&line), Some(record));
+
+        let record = TraverseRecord { uid: 155, path };
+        assert_eq!(TraverseRecord::parse(&record.to_line()), Some(record));
+    }
+
+    let record = HeldGrant { pid: 4242, uid: 155, perms: 6, path: "/home/me/tab\tfile".into() };
+    assert_eq!(HeldGrant::parse(&record.to_line()), Some(record));
+    assert_eq!(HeldGrant::parse("4242\t155\txyz\t/home/me"), None);
+
+    // Innermost share of the same user covers a path
+    let share =
+        |uid, mode, path: &str| ShareRecord { uid, user: "ego".into(), mode, path: path.into() };
+    let records = [
+        share(155, ShareMode::ReadOnly, "/a"),
+        share(155, ShareMode::ReadWrite, "/a/b/c"),
+        share(156, ShareMode::ReadWrite, "/a/b"),
+    ];
+    let covering = |path: &str| covering_share(&records, 155, Path::new(path)).map(|r| &r.path);
+    assert_eq!(covering("/a/b/c/d"), Some(&PathBuf::from("/a/b/c")));
+    assert_eq!(covering("/a/b"), Some(&PathBuf::from("/a")));
+    assert_eq!(covering("/ab"), None);
+}
+
+const DESKTOP_ENTRY: &str = "\
+[Desktop Entry]
+Name=Text Editor
+Name[de]=Texteditor
+Exec=editor \"--name=%c\" -\\s%f %i %U %%
+Icon=editor
+Terminal=false
+
+[Desktop Action new-window]
+Exec=editor --new-window
+";
+
+#[test]
+fn test_desktop_entry() {
+    let entry = DesktopEntry::parse(DESKTOP_ENTRY, "/x/editor.desktop".as_ref()).unwrap();
+    assert_eq!(entry.name, "Text Editor");
+    assert_eq!(entry.exec, "editor \"--name=%c\" - %f %i %U %%");
+    assert_eq!(entry.icon, Some("editor".into()));
+    assert!(!entry.terminal);
+
+    assert_eq!(
+        entry.expand_exec(&string_vec!["a.txt", "b c.txt"]).unwrap(),
+        string_vec![
+            "editor",
+            "--name=Text Editor",
+            "-",
+            "a.txt",
+            "--icon",
+            "editor",
+            "a.txt",
+            "b c.txt",
+            "%"
+        ]
+    );
+    assert_eq!(
+        entry.expand_exec(&[]).unwrap(),
+        string_vec!["editor", "--name=Text Editor", "-", "--icon", "editor", "%"]
+    );
+
+    // A single %f cannot take several files
+    let single = DesktopEntry::parse("[Desktop Entry]\nName=V\nExec=viewer %f\n", "/x/v".as_ref());
+    let single = single.unwrap();
+    assert_eq!(single.expand_exec(&string_vec!["/a"]).unwrap(), string_vec!["viewer", "/a"]);
+    assert!(single.expand_exec(&string_vec!["/a", "/b"]).is_err());
+
+    let cwd = env::current_dir().unwrap();
+    assert_eq!(absolute_file("a b.txt").unwrap(), cwd.join("a b.txt").to_str().unwrap());
+    assert_eq!(absolute_file("/etc/hosts").unwrap(), "/etc/hosts");
+    assert_eq!(absolute_file("https://example.com/x").unwrap(), "https://example.com/x");
+
+    assert!(DesktopEntry::parse("[Other]\nExec=foo\n", "/x/y".as_ref()).is_err());
+    assert!(DesktopEntry::parse("[Desktop Entry]\nName=foo\n", "/x/y".as_ref()).is_err());
+}
+
+#[test]
+fn test_rewrite_launcher() {
+    let source = PathBuf::from("/usr/share/applications/editor.desktop");
+    let output = rewrite_launcher(DESKTOP_ENTRY, &string_vec!["ego", "--user=ego"], "ego", &source);
+    snapshot().eq(output, file!["snapshots/launcher.desktop"].raw());
+
+    // Exec= arguments use double quotes only, with % doubled and backslashes escaped twice
+    assert_eq!(
+        exec_quote(&string_vec!["/opt/my apps/ego", "--user=o'\"x", "100%", "a\\b", ""]),
+        r#""/opt/my apps/ego" "--user=o'\\"x" 100%% "a\\\\b" """#
+    );
+    let launcher = rewrite_launcher(
+        "[Desktop Entry]\nName=V\nExec=viewer %f\n",
+        &string_vec!["/opt/my apps/ego", "--user=o\"x%"],
+        "o\"x%",
+        &source,
+    );
+    let entry = DesktopEntry::parse(&launcher, &source).unwrap();
+    assert_eq!(
+        entry.expand_exec(&string_vec!["/a"]).unwrap(),
+        string_vec!["/opt/my apps/ego", "--user=o\"x%", "viewer", "/a"]
+    );
+
+    let args = parse_args(vec!["ego", "-u", "other", "install-launcher", "firefox"]);
+    assert_eq!(args.user, "other");
+    assert_eq!(args.action, Some(Action::InstallLauncher { entry: "firefox".into() }));
+    assert_eq!(args.command, string_vec![]);
+
+    // Only the .desktop suffix is stripped from reverse-DNS ids
+    let id = |name: &str| desktop_id(Path::new(name)).unwrap();
+    assert_eq!(id("org.gnome.Nautilus"), "org.gnome.Nautilus");
+    assert_eq!(id("/usr/share/applications/org.gnome.Nautilus.desktop"), "org.gnome.Nautilus");
+    assert_eq!(id("ego-org.gnome.Nautilus.desktop"), "ego-org.gnome.Nautilus");
+    assert_eq!(id("firefox"), "firefox");
+}
+
+#[test]
+fn test_setup_render() {
+    assert_eq!(
+        replace_word("ego ego-users (ego) /home/ego Ego", "ego", "x"),
+        "x ego-users (x) /home/x Ego"
+    );
+
+    let output = setup_files("alter", "alter-users")
+        .iter()
+        .fold(String::new(), |mut a, f| {
+            writeln!(a, "# {} (mode {:o})\n{}", f.path.display(), f.mode, f.content).unwrap();
+            a
+        });
+    snapshot().eq(output, file!["snapshots/setup_files.txt"]);
+
+    let args = parse_args(vec!["ego", "-u", "alter", "setup", "--dry-run"]);
+    assert_eq!(
+        args.action,
+        Some(Action::Setup { user: "alter".into(), group: "ego-users".into(), dry_run: true })
+    );
 }
 
 #[test]
@@ -142,6 +392,576 @@ fn test_cli_help() {
     snapshot().eq(build_cli().render_help().to_string(), file!["snapshots/ego.help"]);
 }
 
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("plain"), r#""plain""#);
+    assert_eq!(json_string("a\"b\\c\nd\x01"), r#""a\"b\\c\nd\u0001""#);
+}
+
+#[test]
+fn test_format_env() {
+    let vars = string_vec![
+        "DISPLAY=:0",
+        "PULSE_SERVER=unix:/run/user/1000/pulse/native",
+        "X=a \"b\""
+    ];
+    assert_eq!(
+        format_env(&vars, EnvFormat::Sh),
+        "export DISPLAY=:0\nexport PULSE_SERVER=unix:/run/user/1000/pulse/native\nexport X='a \"b\"'\n"
+    );
+    assert_eq!(
+        format_env(&vars, EnvFormat::Json),
+        r#"{"DISPLAY":":0","PULSE_SERVER":"unix:/run/user/1000/pulse/native","X":"a \"b\""}"#
+            .to_owned()
+            + "\n"
+    );
+    assert_eq!(
+        format_env(&vars, EnvFormat::Env),
+        "DISPLAY=\":0\"\nPULSE_SERVER=\"unix:/run/user/1000/pulse/native\"\nX=\"a \\\"b\\\"\"\n"
+    );
+
+    // Helper processes would exit along with 'ego prepare'
+    let args = parse_args(["ego", "--relay", "prepare", "--print-env"]);
+    let err = check_prepare_args(&args).unwrap_err();
+    assert_eq!(err.message(), "--relay cannot be used with 'ego prepare'");
+    assert!(check_prepare_args(&parse_args(["ego", "prepare"])).is_ok());
+    assert!(check_prepare_args(&parse_args(["ego", "--relay", "true"])).is_ok());
+}
+
+#[test]
+fn test_journal_field() {
+    let mut buf = Vec::new();
+    journal_field(&mut buf, "MESSAGE", "hello");
+    journal_field(&mut buf, "EGO_COMMAND", "a\nb");
+    assert_eq!(buf, b"MESSAGE=hello\nEGO_COMMAND\n\x03\0\0\0\0\0\0\0a\nb\n");
+}
+
+#[test]
+fn test_history_entry() {
+    let entry = HistoryEntry {
+        timestamp: 1_767_875_696,
+        user: "ego".into(),
+        method: "machinectl".into(),
+        cwd: Some("/tmp/dir\twith\\odd\nname".into()),
+        args: string_vec![
+            "--share",
+            "/tmp/My Files",
+            "sh",
+            "-c",
+            "echo 1\necho\t2 \\n"
+        ],
+    };
+    let line = entry.to_line();
+    assert!(!line.contains('\n'));
+    assert_eq!(line.matches('\t').count(), 4);
+    assert_eq!(HistoryEntry::parse(&line), Some(entry));
+    assert_eq!(HistoryEntry::parse("garbage"), None);
+
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+    assert_eq!(format_timestamp(951_825_600), "2000-02-29 12:00:00");
+    assert_eq!(format_timestamp(1_767_875_696), "2026-01-08 12:34:56");
+}
+
+#[test]
+fn test_ego_error() {
+    let err = EgoError::Wayland(ErrorWithHint::new("bad socket".into(), "try this".into()).into());
+    assert_eq!(err.exit_code(), 5);
+    assert_eq!(err.hint(), Some("try this"));
+    assert!(
+        err.to_string()
+            .starts_with("Error preparing Wayland: bad socket\n")
+    );
+
+    let err = EgoError::User("Unknown user 'nope'".into());
+    assert_eq!(err.to_string(), "Unknown user 'nope'");
+    assert_eq!(err.hint(), None);
+
+    assert_eq!(exit_code(&err.into()), 3);
+
+    // Hints of grant errors are preserved
+    let err = EgoError::Device(ErrorWithHint::new("denied".into(), "use setfacl".into()).into());
+    assert_eq!(err.exit_code(), 11);
+    assert_eq!(err.subsystem(), "device");
+    assert_eq!(err.message(), "Error granting device access: denied");
+    assert_eq!(err.hint(), Some("use setfacl"));
+
+    let other: AnyErr = "something else".into();
+    assert_eq!(exit_code(&other), 1);
+}
+
+#[test]
+fn test_message_format() {
+    assert_eq!(parse_args(vec!["ego"]).message_format, MessageFormat::Human);
+    let args = parse_args(vec!["ego", "--message-format=json", "xdg-open"]);
+    assert_eq!(args.message_format, MessageFormat::Json);
+
+    let err = EgoError::X11(ErrorWithHint::new("no libxcb".into(), "install it".into()).into());
+    assert_eq!(err.subsystem(), "x11");
+    assert_eq!(err.message(), "Error preparing X11: no libxcb");
+    assert_eq!(
+        json_message("error", err.subsystem(), &err.message(), err.hint()),
+        r#"{"level":"error","subsystem":"x11","message":"Error preparing X11: no libxcb","hint":"install it"}"#
+    );
+    assert_eq!(
+        json_message("warning", "ego", "careful", None),
+        r#"{"level":"warning","subsystem":"ego","message":"careful","hint":null}"#
+    );
+}
+
+#[test]
+fn test_error_dialog() {
+    let text = error_text("Unknown user 'nope'", Some("Create the user"));
+    assert_eq!(text, "Unknown user 'nope'\n\nHint: Create the user");
+    assert_eq!(error_text("Failed", None), "Failed");
+
+    let commands = error_commands(&text);
+    let programs: Vec<&str> = commands.iter().map(|(program, _)| *program).collect();
+    assert_eq!(programs, ["notify-send", "zenity", "kdialog"]);
+    assert!(commands.iter().all(|(_, args)| args.contains(&text)));
+
+    let args = parse_args(vec!["ego", "askpass", "[sudo] password for me: "]);
+    assert_eq!(
+        args.action,
+        Some(Action::Askpass { prompt: Some("[sudo] password for me: ".into()) })
+    );
+    let commands = password_commands("Password:");
+    assert_eq!(commands[0].0, "zenity");
+    assert!(
+        commands
+            .iter()
+            .all(|(_, args)| args.contains(&"Password:".to_string()))
+    );
+}
+
+#[test]
+fn test_relay() {
+    let args = parse_args(vec![
+        "ego",
+        "relay",
+        "--parent",
+        "1",
+        "/tmp/r",
+        "wayland-0=/run/w",
+    ]);
+    assert_eq!(
+        args.action,
+        Some(Action::Relay {
+            parent: 1,
+            dir: "/tmp/r".into(),
+            sockets: string_vec!["wayland-0=/run/w"]
+        })
+    );
+    assert!(parse_args(vec!["ego", "--relay"]).relay);
+
+    let socket = RelaySocket {
+        name: "pulse-native",
+        target: "/run/user/1000/pulse/native".into(),
+        env_prefix: "PULSE_SERVER=unix:",
+    };
+    assert_eq!(socket.env(Path::new("/tmp/r")), "PULSE_SERVER=unix:/tmp/r/pulse-native");
+    assert_eq!(socket.to_arg(), "pulse-native=/run/user/1000/pulse/native");
+
+    // Data and file descriptors are forwarded
+    let (client, relay_in) = UnixStream::pair().unwrap();
+    let (relay_out, server) = UnixStream::pair().unwrap();
+    let file = File::open("/dev/null").unwrap();
+    let fds = [file.as_raw_fd()];
+    let iov = [IoSlice::new(b"hello")];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+    sendmsg::<()>(client.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    forward(&relay_in, &relay_out).unwrap();
+
+    let mut buf = [0u8; 16];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; 1]);
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let msg = recvmsg::<()>(server.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), MsgFlags::empty())
+        .unwrap();
+    assert_eq!(msg.bytes, 5);
+    assert!(
+        matches!(msg.cmsgs().unwrap().next(), Some(ControlMessageOwned::ScmRights(fds)) if fds.len() == 1)
+    );
+}
+
+#[test]
+fn test_accessibility() {
+    // Off unless requested
+    assert!(!parse_args(vec!["ego"]).a11y);
+    assert!(parse_args(vec!["ego", "--a11y"]).a11y);
+
+    assert_eq!(
+        parse_bus_address("unix:path=/run/user/1000/at-spi/bus_0,guid=1234"),
+        Some("/run/user/1000/at-spi/bus_0".into())
+    );
+    assert_eq!(parse_bus_address("unix:abstract=/tmp/dbus-abc,guid=1234"), None);
+    assert_eq!(parse_bus_address("tcp:host=localhost,port=1234"), None);
+
+    // UID is ASCII decimal, hex encoded
+    assert_eq!(
+        rewrite_auth_line(b"\0AUTH EXTERNAL 31303031\r\n", 1000),
+        Some(b"\0AUTH EXTERNAL 31303030\r\n".to_vec())
+    );
+    assert_eq!(rewrite_auth_line(b"DATA 31303031\r\n", 0), Some(b"DATA 30\r\n".to_vec()));
+    assert_eq!(rewrite_auth_line(b"NEGOTIATE_UNIX_FD\r\n", 1000), None);
+    assert_eq!(rewrite_auth_line(b"BEGIN\r\n", 1000), None);
+}
+
+#[test]
+fn test_input_method() {
+    assert!(!parse_args(vec!["ego"]).ibus);
+    assert!(parse_args(vec!["ego", "--ibus"]).ibus);
+
+    assert_eq!(detect_input_method(&["ibus", "ibus", "@im=ibus"]), Some(InputMethod::IBus));
+    assert_eq!(detect_input_method(&["@im=fcitx"]), Some(InputMethod::Fcitx));
+    assert_eq!(detect_input_method(&["fcitx5"]), Some(InputMethod::Fcitx));
+    assert_eq!(detect_input_method(&["@im=none", "xim"]), None);
+    assert_eq!(detect_input_method(&[]), None);
+
+    assert_eq!(
+        input_method_env(InputMethod::IBus, false, true),
+        string_vec![
+            "XMODIFIERS=@im=ibus",
+            "GTK_IM_MODULE=ibus",
+            "QT_IM_MODULE=ibus"
+        ]
+    );
+    assert_eq!(
+        input_method_env(InputMethod::Fcitx, false, false),
+        string_vec![
+            "XMODIFIERS=@im=fcitx",
+            "GTK_IM_MODULE=xim",
+            "QT_IM_MODULE=xim"
+        ]
+    );
+    // Wayland text-input protocol, no toolkit modules
+    assert_eq!(
+        input_method_env(InputMethod::IBus, true, false),
+        string_vec!["XMODIFIERS=@im=ibus"]
+    );
+}
+
+#[test]
+fn test_runtime_dir_script() {
+    let dir = env::temp_dir().join(format!("ego-test-runtime-{}", std::process::id()));
+    let run = || {
+        Command::new("/bin/sh")
+            .args(["-c", &setup_script(&string_vec![RUNTIME_DIR_SCRIPT]), "sh"])
+            .args(["sh", "-c", "echo \"$XDG_RUNTIME_DIR\""])
+            .env("XDG_RUNTIME_DIR", &dir)
+            .output()
+            .unwrap()
+    };
+    let output = run();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), dir.to_str().unwrap());
+    assert_eq!(dir.metadata().unwrap().permissions().mode() & 0o777, 0o700);
+
+    // Symlinks are not accepted
+    fs::remove_dir(&dir).unwrap();
+    symlink("/tmp", &dir).unwrap();
+    let output = run();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("could not create runtime dir"));
+    fs::remove_file(&dir).unwrap();
+
+    // The private dir does not depend on the invoking user's TMPDIR
+    let _env = env_lock::lock_env([("TMPDIR", Some("/home/me/tmp"))]);
+    let ctx = EgoContext { target_uid: 4_242_424, ..test_context() };
+    assert_eq!(sudo_runtime_dir(&ctx), SudoRuntimeDir::Private("/tmp/ego-runtime-4242424".into()));
+}
+
+#[test]
+fn test_flatpak() {
+    let args = parse_args(vec![
+        "ego",
+        "--flatpak",
+        "org.mozilla.firefox",
+        "https://example.com",
+    ]);
+    assert_eq!(args.flatpak.as_deref(), Some("org.mozilla.firefox"));
+    assert_eq!(args.command, string_vec!["https://example.com"]);
+
+    assert!(validate_app_id("org.mozilla.firefox").is_ok());
+    assert!(validate_app_id("com.github.Some_App-1").is_ok());
+    assert!(validate_app_id("firefox").is_err());
+    assert!(validate_app_id("org..firefox").is_err());
+    assert!(validate_app_id("org.mozilla.2fox").is_err());
+    assert!(validate_app_id("../../etc").is_err());
+
+    let dir = env::temp_dir().join(format!("ego-test-flatpak-{}", std::process::id()));
+    fs::create_dir_all(dir.join("system/app/org.example.App/current")).unwrap();
+    let installations = [dir.join("user"), dir.join("system")];
+    assert!(check_installed("org.example.App", &installations).is_ok());
+    let err = check_installed("org.example.Missing", &installations).unwrap_err();
+    assert!(err.to_string().contains("not installed"));
+
+    // Bus in runtime dir is used, activation environment updated
+    let _listener = UnixListener::bind(dir.join("bus")).unwrap();
+    let fake_update = dir.join("dbus-update-activation-environment");
+    fs::write(&fake_update, "#!/bin/sh\necho \"update $*\"\n").unwrap();
+    fs::set_permissions(&fake_update, fs::Permissions::from_mode(0o755)).unwrap();
+    let output = Command::new("/bin/sh")
+        .args(["-c", &setup_script(&[session_bus_script(&["DISPLAY"])])])
+        .args(["sh", "sh", "-c", "echo \"$DBUS_SESSION_BUS_ADDRESS\""])
+        .env("XDG_RUNTIME_DIR", &dir)
+        .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+        .output()
+        .unwrap();
+    let expected = format!("update --systemd DISPLAY\nunix:path={}/bus\n", dir.display());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_machine() {
+    let args = parse_args(vec!["ego", "--machine", "fedora", "firefox"]);
+    assert_eq!(args.machine.as_deref(), Some("fedora"));
+
+    assert!(validate_machine_name("fedora-40").is_ok());
+    assert!(validate_machine_name(".host").is_err());
+    assert!(validate_machine_name("--help").is_err());
+    assert!(validate_machine_name("a/b").is_err());
+
+    assert_eq!(
+        split_env_path("/run/user/1000/wayland-0"),
+        Some(("", "/run/user/1000/wayland-0", ""))
+    );
+    assert_eq!(
+        split_env_path("unix:/run/user/1000/pulse/native"),
+        Some(("unix:", "/run/user/1000/pulse/native", ""))
+    );
+    assert_eq!(
+        split_env_path("unix:path=/run/user/1000/bus,guid=1234"),
+        Some(("unix:path=", "/run/user/1000/bus", ",guid=1234"))
+    );
+    assert_eq!(split_env_path(":0"), None);
+    assert_eq!(split_env_path("unix:abstract=/tmp/dbus"), None);
+
+    // Only existing paths are bound
+    let (env, binds) = translate_env(&string_vec![
+        "PULSE_SERVER=unix:/dev/null",
+        "WAYLAND_DISPLAY=/nonexistent/wayland-0",
+        "DISPLAY=:0"
+    ]);
+    assert_eq!(
+        env,
+        string_vec![
+            "PULSE_SERVER=unix:/run/ego/PULSE_SERVER",
+            "WAYLAND_DISPLAY=/nonexistent/wayland-0",
+            "DISPLAY=:0"
+        ]
+    );
+    assert_eq!(
+        binds,
+        [Bind {
+            key: "PULSE_SERVER".into(),
+            host: "/dev/null".into(),
+            target: "/run/ego/PULSE_SERVER".into()
+        }]
+    );
+
+    // Helpers that run ego as target user are not available in the machine
+    for option in ["--notifications", "--open-links"] {
+        let args = ["ego", "--machine", "fedora", option, "firefox"];
+        assert!(build_cli().try_get_matches_from(args).is_err());
+    }
+}
+
+#[test]
+fn test_device() {
+    let args = parse_args(vec!["ego", "--device", "camera", "--device=/dev/null", "app"]);
+    assert_eq!(args.device, string_vec!["camera", "/dev/null"]);
+    // Only device nodes of the named classes can be passed on
+    assert!(resolve_devices("/dev/null").is_err());
+    assert!(resolve_devices("/etc/hostname").is_err());
+    assert!(resolve_devices("toaster").is_err());
+
+    // Fake sysfs and /dev: event1 is a joystick, event0 is a keyboard
+    let root = env::temp_dir().join(format!("ego-test-device-{}", std::process::id()));
+    let (sysfs, dev) = (root.join("sys"), root.join("dev"));
+    for dir in ["event0/device", "event1/device/js0", "js0", "mouse0"] {
+        fs::create_dir_all(sysfs.join(dir)).unwrap();
+    }
+    fs::create_dir_all(&dev).unwrap();
+    for node in ["event0", "event1", "js0", "mouse0"] {
+        File::create(dev.join(node)).unwrap();
+    }
+    assert_eq!(class_devices(&sysfs, &dev, "gamepad"), [dev.join("event1"), dev.join("js0")]);
+    assert_eq!(class_devices(&sysfs, &dev, "audio").len(), 4);
+    fs::remove_dir_all(&root).unwrap();
+
+    // Root-owned device nodes get a setfacl hint
+    let denied: AnyErr = io::Error::from(io::ErrorKind::PermissionDenied).into();
+    assert!(is_permission_denied(&denied));
+    assert!(!is_permission_denied(&"other".into()));
+
+    // Failing to run the root helper explains how to allow it
+    let paths = [PathBuf::from("/dev/null"), PathBuf::from("/dev/zero")];
+    let err = device_error("sudo: a password is required", "ego", &paths);
+    let err = err.downcast_ref::<ErrorWithHint>().unwrap();
+    assert_eq!(err.message(), "Could not change device ACLs as root: sudo: a password is required");
+    assert_eq!(
+        err.hint(),
+        "Run 'sudo ego setup' to allow granting devices, or grant access with: \
+        sudo setfacl -m u:ego:rw /dev/null /dev/zero"
+    );
+
+    // The helper refuses paths that are not class devices, even through symlinks
+    assert!(check_device(Path::new("/dev/null"), 0).is_err());
+    assert!(check_device(Path::new("/dev/../etc/passwd"), 0).is_err());
+    assert!(check_device(Path::new("/dev/stdin"), 0).is_err());
+
+    let args = parse_args(vec![
+        "ego",
+        "device-acl",
+        "--user",
+        "ego",
+        "--revoke",
+        "/dev/video0",
+    ]);
+    assert_eq!(
+        args.action,
+        Some(Action::DeviceAcl {
+            user: "ego".into(),
+            revoke: true,
+            paths: vec!["/dev/video0".into()]
+        })
+    );
+}
+
+#[test]
+fn test_agent_forwarding() {
+    let args = parse_args(vec!["ego", "--ssh-agent", "--gpg-agent", "git"]);
+    assert!(args.ssh_agent && args.gpg_agent);
+    assert!(!parse_args(vec!["ego"]).ssh_agent);
+
+    // gpg-agent setup script links the agent socket in a per-launch GnuPG home
+    let dir = env::temp_dir().join(format!("ego-test-gpg-{}", std::process::id()));
+    let home = dir.join("gnupg");
+    fs::create_dir_all(&home).unwrap();
+    File::create(home.join("pubring.kbx")).unwrap();
+    File::create(home.join("S.gpg-agent")).unwrap();
+    let fake_gpgconf = format!(
+        "gpgconf() {{ case $2 in homedir) echo {0};; *) echo \"${{GNUPGHOME:-{0}}}/S.gpg-agent\";; esac; }}; ",
+        home.display()
+    );
+    let script = fake_gpgconf + GPG_SETUP_SCRIPT + "; echo \"$GNUPGHOME\"";
+    let output = Command::new("/bin/sh")
+        .args(["-c", &script])
+        .env("EGO_GPG_AGENT_SOCK", "/run/user/1000/gnupg/S.gpg-agent.extra")
+        .env("XDG_RUNTIME_DIR", &dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let gnupghome = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+    assert_eq!(gnupghome.parent(), Some(dir.as_path()));
+    let link = fs::read_link(gnupghome.join("S.gpg-agent")).unwrap();
+    assert_eq!(link, Path::new("/run/user/1000/gnupg/S.gpg-agent.extra"));
+    assert_eq!(fs::read_link(gnupghome.join("pubring.kbx")).unwrap(), home.join("pubring.kbx"));
+    // The target user's own agent socket is untouched
+    assert!(
+        home.join("S.gpg-agent")
+            .symlink_metadata()
+            .unwrap()
+            .is_file()
+    );
+
+    // With --relay, ego provides the home in the relay dir, which the relay removes on exit
+    let relay_home = dir.join("ego-relay-1234/gnupg");
+    fs::create_dir_all(&relay_home).unwrap();
+    let output = Command::new("/bin/sh")
+        .args(["-c", &script])
+        .env("EGO_GPG_AGENT_SOCK", "/tmp/ego-relay-1234/gpg-agent")
+        .env("EGO_GNUPGHOME", &relay_home)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), relay_home.to_str().unwrap());
+    assert_eq!(
+        fs::read_link(relay_home.join("S.gpg-agent")).unwrap(),
+        Path::new("/tmp/ego-relay-1234/gpg-agent")
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_notifications() {
+    assert_eq!(parse_args(vec!["ego", "--notifications"]).notifications, Some(NotifyMode::Plain));
+    assert_eq!(
+        parse_args(vec!["ego", "--notifications=prefix"]).notifications,
+        Some(NotifyMode::Prefix)
+    );
+    assert_eq!(parse_args(vec!["ego"]).notifications, None);
+    let args = parse_args(vec!["ego", "notify-proxy", "/tmp/r/notifications"]);
+    assert_eq!(args.action, Some(Action::NotifyProxy { socket: "/tmp/r/notifications".into() }));
+
+    assert_eq!(prefix_summary(Some("ego"), "New message"), "[ego] New message");
+    assert_eq!(prefix_summary(None, "New message"), "New message");
+
+    // Hints and icons that make the daemon open files are dropped
+    let hints: HashMap<String, u8> = [
+        ("urgency", 1),
+        ("image-path", 2),
+        ("sound-file", 3),
+        ("category", 4),
+    ]
+    .map(|(k, v)| (k.to_string(), v))
+    .into();
+    let mut kept: Vec<_> = filter_hints(hints).into_keys().collect();
+    kept.sort();
+    assert_eq!(kept, ["category", "urgency"]);
+    assert_eq!(filter_icon("mail-unread".into()), "mail-unread");
+    assert_eq!(filter_icon("file:///home/me/secret.png".into()), "");
+    assert_eq!(filter_icon("/home/me/secret.png".into()), "");
+    assert_eq!(strip_images("a <b>b</b><IMG src=\"/etc/x\"/> c"), "a <b>b</b> c");
+    assert_eq!(strip_images("a <img src="), "a ");
+
+    // Proxy is started in the background, then the command runs
+    let setup = string_vec![proxy_setup_command(
+        "/usr/bin/ego",
+        Path::new("/tmp/r/notifications")
+    )];
+    assert_eq!(
+        setup[0],
+        "(/usr/bin/ego notify-proxy /tmp/r/notifications </dev/null >/dev/null &)"
+    );
+    let output = Command::new("/bin/sh")
+        .args(["-n", "-c", &setup_script(&setup)])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // With sudo, a user without a logind session has no bus for the proxy
+    let ctx = EgoContext { target_uid: 4_242_424, ..test_context() };
+    let err = check_session_bus(&ctx, Method::Sudo).unwrap_err();
+    assert!(err.message().contains("has no session bus"), "{err}");
+    assert!(err.hint().contains("enable-linger"), "{err}");
+    assert!(check_session_bus(&ctx, Method::Machinectl).is_ok());
+}
+
+#[test]
+fn test_open_links() {
+    assert!(parse_args(vec!["ego", "--open-links", "firefox"]).open_links);
+    let args = parse_args(vec!["ego", "open-url", "--", "-x"]);
+    assert_eq!(args.action, Some(Action::OpenUrl { args: string_vec!["-x"] }));
+
+    assert!(is_handoff_url("https://example.com/?q=a b"));
+    assert!(is_handoff_url("HTTP://example.com"));
+    assert!(is_handoff_url("mailto:someone@example.com"));
+    assert!(!is_handoff_url("file:///etc/passwd"));
+    assert!(!is_handoff_url("/home/ego/file.pdf"));
+    assert!(!is_handoff_url("https:"));
+    assert!(!is_handoff_url("https://example.com/\nhttps://evil.example.com"));
+    assert!(!is_handoff_url(&format!("https://{}", "a".repeat(9000))));
+    // Mail clients could attach the invoking user's files
+    assert!(is_handoff_url("mailto:a@example.com?subject=Hi&body=attach"));
+    assert!(!is_handoff_url("mailto:a@example.com?attach=/home/me/.ssh/id_ed25519"));
+    assert!(!is_handoff_url("MAILTO:a@example.com?subject=x&Attachment=~/.gnupg"));
+    assert!(!is_handoff_url("mailto:a@example.com?%61ttach=/etc/shadow"));
+
+    assert_eq!(shim_script("/usr/bin/ego"), "#!/bin/sh\nexec /usr/bin/ego open-url \"$@\"\n");
+    assert_eq!(confirm_commands("Open?")[1].1, string_vec!["--title", "Ego", "--yesno", "Open?"]);
+}
+
 #[test]
 fn test_have_command() {
     assert!(have_command("sh"));
@@ -157,6 +977,11 @@ fn test_check_user_homedir() {
         target_uid: 0,
         target_user_shell: PathBuf::default(),
         target_user_homedir: "/root".into(),
+        dry_run: false,
+        relay: false,
+        a11y: false,
+        ibus: false,
+        machine: None,
     };
 
     // Capture log output from called functions
@@ -183,3 +1008,20 @@ fn test_check_user_homedir() {
 
     assert_log_snapshot(&file!["snapshots/check_user_homedir.txt"]);
 }
+
+#[test]
+fn test_sudo_allows() {
+    let listing = "\
+Matching Defaults entries for me on host:
+    env_reset
+
+User me may run the following commands on host:
+    (ego) NOPASSWD: ALL
+    (root) ALL
+";
+    assert!(sudo_allows(listing, "ego"));
+    assert!(!sudo_allows(listing, "root"));
+    assert!(!sudo_allows(listing, "other"));
+    assert!(sudo_allows("    (ALL : ALL) NOPASSWD: ALL\n", "other"));
+    assert!(sudo_allows("    (alice, ego) NOPASSWD: /usr/bin/true\n", "ego"));
+}
@@ -1,18 +1,95 @@
-use crate::ErrorWithHint;
+use crate::errors::ErrorWithHint;
+use crate::logging;
 use anstyle::Style;
 use log::debug;
-use std::fmt::Display;
+use std::ffi::OsStr;
+use std::fmt::{Display, Write};
 use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::prelude::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use std::{env, io};
 
 /// Paint string `content` with ANSI colors `style` for printing to console.
+/// Plain text with `--message-format=json`.
 pub fn paint(style: Style, content: impl Display) -> String {
+    if logging::json_output() {
+        return content.to_string();
+    }
     format!("{}{content}{}", style.render(), style.render_reset())
 }
 
+/// Quote and escape `value` as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Backslash-escape tabs and newlines, so that a field fits on one tab-separated line.
+/// Bytes that are not valid UTF-8 are written as `\xHH`.
+pub fn escape_field(value: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for chunk in value.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c => out.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(out, "\\x{byte:02x}").unwrap();
+        }
+    }
+    out
+}
+
+/// Reverse of [`escape_field`].
+pub fn unescape_field(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let hex = value
+            .get(i + 2..i + 4)
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes.get(i + 1), hex) {
+            (Some(b'x'), Some(byte)) => {
+                out.push(byte);
+                i += 2;
+            }
+            (Some(b'n'), _) => out.push(b'\n'),
+            (Some(b't'), _) => out.push(b'\t'),
+            (Some(b'r'), _) => out.push(b'\r'),
+            (Some(&other), _) => out.push(other),
+            (None, _) => out.push(b'\\'),
+        }
+        i += 2;
+    }
+    out
+}
+
 /// Detect if system was booted with systemd init system. Same logic as `sd_booted()` in libsystemd.
 /// <https://www.freedesktop.org/software/systemd/man/sd_booted.html>
 pub fn sd_booted() -> bool {
@@ -26,6 +103,38 @@ pub fn have_command<P: AsRef<Path>>(exe_name: P) -> bool {
         .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(&exe_name).is_file()))
 }
 
+/// System-wide directories where helpers run as the target user look for the `ego` executable
+const SYSTEM_BIN_DIRS: [&str; 3] = ["/usr/local/bin", "/usr/bin", "/bin"];
+
+/// Path of an `ego` executable that other users can run, for helpers started as the target user.
+/// Our own executable, e.g. in `~/.cargo/bin`, is often not accessible to them.
+pub fn system_ego() -> Result<PathBuf, ErrorWithHint> {
+    let runnable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.mode() & 0o001 != 0)
+    };
+    let exe = env::current_exe().ok();
+    if let Some(exe) = exe.as_ref().filter(|exe| {
+        exe.parent()
+            .is_some_and(|dir| SYSTEM_BIN_DIRS.iter().any(|d| dir == Path::new(d)))
+            && runnable(exe)
+    }) {
+        return Ok(exe.clone());
+    }
+    if let Some(path) = SYSTEM_BIN_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join("ego"))
+        .find(|path| runnable(path))
+    {
+        return Ok(path);
+    }
+    let exe = exe.map_or_else(|| "ego".to_string(), |exe| exe.display().to_string());
+    Err(ErrorWithHint::new(
+        "ego is not installed system-wide, the target user cannot run it".into(),
+        format!("Install it with: sudo install -m755 {exe} /usr/local/bin/ego"),
+    ))
+}
+
 fn report_command_error(err: &io::Error, program: &str, args: &[String]) -> ErrorWithHint {
     ErrorWithHint::new(
         format!("Failed to run {program}: {err}"),
@@ -39,9 +148,21 @@ fn report_command_error(err: &io::Error, program: &str, args: &[String]) -> Erro
 
 /// Exec command (ending the current process) or return error.
 pub fn exec_command(program: &str, args: &[String]) -> Result<(), ErrorWithHint> {
+    exec_command_env(program, args, &[])
+}
+
+/// Exec command with additional environment variables `env`.
+pub fn exec_command_env(
+    program: &str,
+    args: &[String],
+    env: &[(&str, &OsStr)],
+) -> Result<(), ErrorWithHint> {
     debug!("Executing: {program} {}", shell_words::join(args));
     // If this call returns at all, it was an error
-    let err = Command::new(program).args(args).exec();
+    let err = Command::new(program)
+        .args(args)
+        .envs(env.iter().copied())
+        .exec();
 
     Err(report_command_error(&err, program, args))
 }
@@ -1,8 +1,118 @@
-complete -c ego -s u -l user -d 'Specify a username (default: ego)' -r -f -a "(__fish_complete_users)"
-complete -c ego -l sudo -d 'Use \'sudo\' to change user'
-complete -c ego -l machinectl -d 'Use \'machinectl\' to change user (default, if available)'
-complete -c ego -l machinectl-bare -d 'Use \'machinectl\' but skip xdg-desktop-portal setup'
-complete -c ego -l old-xhost -d 'Execute \'xhost\' command instead of connecting to X11 directly'
-complete -c ego -s v -l verbose -d 'Verbose output. Use multiple times for more output.'
-complete -c ego -s h -l help -d 'Print help'
-complete -c ego -s V -l version -d 'Print version'
+# Print an optspec for argparse to handle cmd's options that are independent of any subcommand.
+function __fish_ego_global_optspecs
+    string join \n u/user= sudo machinectl machinectl-bare machine= old-xhost share= unshare= device= ssh-agent gpg-agent notifications= open-links list-shares grant-args= desktop-file= flatpak= dry-run relay a11y ibus v/verbose message-format= h/help V/version
+end
+
+function __fish_ego_needs_command
+    # Figure out if the current invocation already has a command.
+    set -l cmd (commandline -opc)
+    set -e cmd[1]
+    argparse -s (__fish_ego_global_optspecs) -- $cmd 2>/dev/null
+    or return
+    if set -q argv[1]
+        # Also print the command, so this can be used to figure out what it is.
+        echo $argv[1]
+        return 1
+    end
+    return 0
+end
+
+function __fish_ego_using_subcommand
+    set -l cmd (__fish_ego_needs_command)
+    test -z "$cmd"
+    and return 1
+    contains -- $cmd[1] $argv
+end
+
+complete -c ego -n "__fish_ego_needs_command" -s u -l user -d 'Specify a username (default: ego)' -r -f -a "(__fish_complete_users)"
+complete -c ego -n "__fish_ego_needs_command" -l machine -d 'Launch in a systemd-nspawn machine or container instead of the host' -r
+complete -c ego -n "__fish_ego_needs_command" -l share -d 'Grant target user access to a file or directory tree (default: ro)' -r -F
+complete -c ego -n "__fish_ego_needs_command" -l unshare -d 'Revoke access previously granted with --share or --device' -r -F
+complete -c ego -n "__fish_ego_needs_command" -l device -d 'Grant target user access to a device node or class: camera, gamepad, audio' -r -F
+complete -c ego -n "__fish_ego_needs_command" -l notifications -d 'Show target user\'s notifications on your desktop, optionally prefixed' -r -f -a "plain\t''
+prefix\t''"
+complete -c ego -n "__fish_ego_needs_command" -l grant-args -d 'Grant target user access to files given as command arguments' -r -f -a "ro\t''
+rw\t''"
+complete -c ego -n "__fish_ego_needs_command" -l desktop-file -d 'Run application from .desktop file or application id, command is file list' -r -F
+complete -c ego -n "__fish_ego_needs_command" -l flatpak -d 'Run Flatpak application, command is its arguments' -r
+complete -c ego -n "__fish_ego_needs_command" -l message-format -d 'Output errors and warnings as text or as JSON lines on stderr' -r -f -a "human\t''
+json\t''"
+complete -c ego -n "__fish_ego_needs_command" -l sudo -d 'Use \'sudo\' to change user'
+complete -c ego -n "__fish_ego_needs_command" -l machinectl -d 'Use \'machinectl\' to change user (default, if available)'
+complete -c ego -n "__fish_ego_needs_command" -l machinectl-bare -d 'Use \'machinectl\' but skip xdg-desktop-portal setup'
+complete -c ego -n "__fish_ego_needs_command" -l old-xhost -d 'Execute \'xhost\' command instead of connecting to X11 directly'
+complete -c ego -n "__fish_ego_needs_command" -l ssh-agent -d 'Forward your SSH agent to target user (target can use all your keys)'
+complete -c ego -n "__fish_ego_needs_command" -l gpg-agent -d 'Forward your GnuPG agent\'s restricted extra socket to target user'
+complete -c ego -n "__fish_ego_needs_command" -l open-links -d 'Open links from target user\'s applications in your browser, after asking'
+complete -c ego -n "__fish_ego_needs_command" -l list-shares -d 'List paths shared with --share'
+complete -c ego -n "__fish_ego_needs_command" -l dry-run -d 'Print planned permission changes and command without changing anything'
+complete -c ego -n "__fish_ego_needs_command" -l relay -d 'Relay display and audio sockets instead of granting access to runtime dir'
+complete -c ego -n "__fish_ego_needs_command" -l a11y -d 'Relay your accessibility bus for screen readers. Warning: target user can read and control your accessible applications'
+complete -c ego -n "__fish_ego_needs_command" -l ibus -d 'Relay your IBus input method bus on X11 instead of using XIM. Warning: target user can see what you type'
+complete -c ego -n "__fish_ego_needs_command" -s v -l verbose -d 'Verbose output. Use multiple times for more output.'
+complete -c ego -n "__fish_ego_needs_command" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_needs_command" -s V -l version -d 'Print version'
+complete -c ego -n "__fish_ego_needs_command" -a "install-launcher" -d 'Install a copy of a desktop entry that launches the application via ego'
+complete -c ego -n "__fish_ego_needs_command" -a "uninstall-launcher" -d 'Remove a desktop entry installed with install-launcher'
+complete -c ego -n "__fish_ego_needs_command" -a "setup" -d 'Create target user and install sudoers and polkit rules (run as root)'
+complete -c ego -n "__fish_ego_needs_command" -a "doctor" -d 'Diagnose common setup problems without launching anything'
+complete -c ego -n "__fish_ego_needs_command" -a "prepare" -d 'Grant access to display and audio, but do not launch a command'
+complete -c ego -n "__fish_ego_needs_command" -a "history" -d 'List previous launches'
+complete -c ego -n "__fish_ego_needs_command" -a "again" -d 'Re-run a previous launch with the same options'
+complete -c ego -n "__fish_ego_needs_command" -a "askpass" -d 'Ask for password with a graphical dialog (used as SUDO_ASKPASS)'
+complete -c ego -n "__fish_ego_needs_command" -a "relay" -d 'Relay sockets for --relay mode (started by ego itself)'
+complete -c ego -n "__fish_ego_needs_command" -a "revoke" -d 'Revoke access granted for a launch (started by ego itself)'
+complete -c ego -n "__fish_ego_needs_command" -a "notify-bridge" -d 'Forward notifications to your desktop (started by ego itself)'
+complete -c ego -n "__fish_ego_needs_command" -a "notify-proxy" -d 'Forward target user\'s notifications to the bridge (started by ego itself)'
+complete -c ego -n "__fish_ego_needs_command" -a "open-listener" -d 'Open target user\'s links in your browser (started by ego itself)'
+complete -c ego -n "__fish_ego_needs_command" -a "device-acl" -d 'Grant target user access to your devices, as root (run by ego via sudo)'
+complete -c ego -n "__fish_ego_needs_command" -a "open-url" -d 'Open link in the invoking user\'s browser (xdg-open replacement)'
+complete -c ego -n "__fish_ego_needs_command" -a "help" -d 'Print this message or the help of the given subcommand(s)'
+complete -c ego -n "__fish_ego_using_subcommand install-launcher" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand uninstall-launcher" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand setup" -l user -d 'Target user to create (default: --user of ego, i.e. \'ego\')' -r -f -a "(__fish_complete_users)"
+complete -c ego -n "__fish_ego_using_subcommand setup" -l group -d 'Group whose members may run commands as target user' -r
+complete -c ego -n "__fish_ego_using_subcommand setup" -l dry-run -d 'Print files and commands without changing anything'
+complete -c ego -n "__fish_ego_using_subcommand setup" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand doctor" -l json -d 'Output results as JSON'
+complete -c ego -n "__fish_ego_using_subcommand doctor" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand prepare" -l format -d 'Format of --print-env: shell, JSON or systemd EnvironmentFile' -r -f -a "sh\t''
+json\t''
+env\t''"
+complete -c ego -n "__fish_ego_using_subcommand prepare" -l print-env -d 'Print environment variables for the target user'
+complete -c ego -n "__fish_ego_using_subcommand prepare" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand history" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand again" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand askpass" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand relay" -l parent -d 'Stop relaying when this process exits' -r
+complete -c ego -n "__fish_ego_using_subcommand relay" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand revoke" -l parent -d 'Revoke when this process exits' -r
+complete -c ego -n "__fish_ego_using_subcommand revoke" -l uid -d 'Target user ID' -r
+complete -c ego -n "__fish_ego_using_subcommand revoke" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand notify-bridge" -l parent -d 'Stop when this process exits' -r
+complete -c ego -n "__fish_ego_using_subcommand notify-bridge" -l prefix -d 'Prefix notification summaries with NAME' -r
+complete -c ego -n "__fish_ego_using_subcommand notify-bridge" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand notify-proxy" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand open-listener" -l parent -d 'Stop when this process exits' -r
+complete -c ego -n "__fish_ego_using_subcommand open-listener" -l user -d 'Target user name shown in confirmation' -r
+complete -c ego -n "__fish_ego_using_subcommand open-listener" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand device-acl" -l user -d 'Target user name' -r
+complete -c ego -n "__fish_ego_using_subcommand device-acl" -l revoke -d 'Remove the target user\'s access instead'
+complete -c ego -n "__fish_ego_using_subcommand device-acl" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand open-url" -s h -l help -d 'Print help'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "install-launcher" -d 'Install a copy of a desktop entry that launches the application via ego'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "uninstall-launcher" -d 'Remove a desktop entry installed with install-launcher'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "setup" -d 'Create target user and install sudoers and polkit rules (run as root)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "doctor" -d 'Diagnose common setup problems without launching anything'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "prepare" -d 'Grant access to display and audio, but do not launch a command'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "history" -d 'List previous launches'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "again" -d 'Re-run a previous launch with the same options'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "askpass" -d 'Ask for password with a graphical dialog (used as SUDO_ASKPASS)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "relay" -d 'Relay sockets for --relay mode (started by ego itself)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "revoke" -d 'Revoke access granted for a launch (started by ego itself)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "notify-bridge" -d 'Forward notifications to your desktop (started by ego itself)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "notify-proxy" -d 'Forward target user\'s notifications to the bridge (started by ego itself)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "open-listener" -d 'Open target user\'s links in your browser (started by ego itself)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "device-acl" -d 'Grant target user access to your devices, as root (run by ego via sudo)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "open-url" -d 'Open link in the invoking user\'s browser (xdg-open replacement)'
+complete -c ego -n "__fish_ego_using_subcommand help; and not __fish_seen_subcommand_from install-launcher uninstall-launcher setup doctor prepare history again askpass relay revoke notify-bridge notify-proxy open-listener device-acl open-url help" -f -a "help" -d 'Print this message or the help of the given subcommand(s)'